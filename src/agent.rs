@@ -1,14 +1,17 @@
 use std::io;
 use std::sync::Arc;
 
-use ssh2::{Agent, BlockDirections, PublicKey, Session};
-use tokio::io::Interest;
-use tokio::net::TcpStream;
+use ssh2::{Agent, PublicKey, Session};
+
+use crate::auth::{self, AuthCallback, AuthMethod};
+use crate::transport::Transport;
+use crate::wait;
 
 pub struct AsyncAgent {
     pub(crate) agent: Agent,
     pub(crate) session: Session,
-    pub(crate) io: Arc<TcpStream>,
+    pub(crate) io: Arc<Transport>,
+    pub(crate) auth_event: Option<AuthCallback>,
 }
 
 impl AsyncAgent {
@@ -16,49 +19,13 @@ impl AsyncAgent {
         &mut self,
         mut op: impl FnMut(&mut Agent) -> io::Result<R>,
     ) -> io::Result<R> {
-        loop {
-            match op(&mut self.agent) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let agent = &mut self.agent;
+        wait::wait_io(&self.session, &self.io, || op(agent)).await
     }
 
     async fn wait_io<R>(&self, mut op: impl FnMut(&Agent) -> io::Result<R>) -> io::Result<R> {
-        loop {
-            match op(&self.agent) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let agent = &self.agent;
+        wait::wait_io(&self.session, &self.io, || op(agent)).await
     }
 
     pub async fn connect(&mut self) -> io::Result<()> {
@@ -80,8 +47,76 @@ impl AsyncAgent {
         self.agent.identities().map_err(Into::into)
     }
 
+    /// `list_identities` and `identities` in one call.
+    pub async fn fetch_identities(&mut self) -> io::Result<Vec<PublicKey>> {
+        self.list_identities().await?;
+        self.identities()
+    }
+
+    /// The OpenSSH style `SHA256:...` fingerprint of `identity`.
+    pub fn fingerprint(identity: &PublicKey) -> String {
+        auth::fingerprint(identity.blob())
+    }
+
+    /// Connect to the agent and try its identities one after the other until
+    /// the server accepts one, which is returned.
+    pub async fn userauth_try_all(&mut self, username: &str) -> io::Result<PublicKey> {
+        self.userauth_try_all_with(username, |_| true).await
+    }
+
+    /// Like [`userauth_try_all`](Self::userauth_try_all), skipping the
+    /// identities `filter` returns `false` for. Servers usually give up after
+    /// a handful of attempts, so this helps with agents holding many keys.
+    pub async fn userauth_try_all_with(
+        &mut self,
+        username: &str,
+        mut filter: impl FnMut(&PublicKey) -> bool,
+    ) -> io::Result<PublicKey> {
+        self.connect().await?;
+        let identities = self.fetch_identities().await?;
+
+        let mut tried = Vec::new();
+        for identity in identities {
+            if !filter(&identity) {
+                continue;
+            }
+
+            match self.userauth(username, &identity).await {
+                Ok(()) => return Ok(identity),
+                // no point in trying more keys over a broken connection
+                Err(e) if self.io.check().is_err() => return Err(e),
+                Err(_) => tried.push(format!(
+                    "{} ({})",
+                    Self::fingerprint(&identity),
+                    identity.comment()
+                )),
+            }
+        }
+
+        let msg = if tried.is_empty() {
+            format!("no agent identities to try for {}", username)
+        } else {
+            format!(
+                "no agent identity accepted for {}, tried {}",
+                username,
+                tried.join(", ")
+            )
+        };
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+    }
+
     pub async fn userauth(&self, username: &str, identity: &PublicKey) -> io::Result<()> {
-        self.wait_io(|agent| agent.userauth(username, identity).map_err(Into::into))
-            .await
+        let res = self
+            .wait_io(|agent| agent.userauth(username, identity).map_err(Into::into))
+            .await;
+
+        auth::emit(
+            &self.auth_event,
+            AuthMethod::Agent,
+            username,
+            Some(auth::fingerprint(identity.blob())),
+            &res,
+        );
+        res
     }
 }
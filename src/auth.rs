@@ -0,0 +1,181 @@
+use std::borrow::Cow;
+use std::future::Future;
+use std::io;
+use std::sync::{mpsc, Arc};
+
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use ssh2::{KeyboardInteractivePrompt, Prompt};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::transport::Transport;
+
+pub(crate) type AuthCallback = Arc<dyn Fn(&AuthEvent<'_>) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Password,
+    KeyboardInteractive,
+    Agent,
+    PublicKeyFile,
+    PublicKeyMemory,
+    HostBasedFile,
+}
+
+/// A single authentication attempt, passed to the callback set with
+/// [`AsyncSession::on_auth_event`](crate::AsyncSession::on_auth_event).
+///
+/// `fingerprint` is the OpenSSH style `SHA256:...` fingerprint of the public
+/// key, when the attempt used one that is known locally. `error` is `None` if
+/// the attempt succeeded, otherwise its kind tells why it failed.
+#[derive(Debug)]
+pub struct AuthEvent<'a> {
+    pub method: AuthMethod,
+    pub username: &'a str,
+    pub fingerprint: Option<String>,
+    pub error: Option<&'a io::Error>,
+}
+
+/// Answers the prompts of
+/// [`AsyncSession::userauth_keyboard_interactive_async`](crate::AsyncSession::userauth_keyboard_interactive_async).
+///
+/// Called once per round of prompts the server sends, and expected to
+/// return exactly one response per prompt.
+pub trait AsyncKeyboardInteractivePrompt {
+    fn prompt(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'_>],
+    ) -> impl Future<Output = Vec<String>> + Send;
+}
+
+pub(crate) struct PromptRequest {
+    pub(crate) username: String,
+    pub(crate) instructions: String,
+    pub(crate) prompts: Vec<Prompt<'static>>,
+    pub(crate) reply: mpsc::Sender<Vec<String>>,
+}
+
+// Runs on the blocking thread driving libssh2, and waits there for the
+// async side to answer. No answer means no responses, which fails the
+// attempt.
+pub(crate) struct PromptBridge {
+    pub(crate) requests: UnboundedSender<PromptRequest>,
+    pub(crate) io: Arc<Transport>,
+}
+
+impl KeyboardInteractivePrompt for PromptBridge {
+    fn prompt<'a>(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        let (reply, response) = mpsc::channel();
+        let request = PromptRequest {
+            username: username.to_string(),
+            instructions: instructions.to_string(),
+            prompts: prompts
+                .iter()
+                .map(|prompt| Prompt {
+                    text: Cow::Owned(prompt.text.to_string()),
+                    echo: prompt.echo,
+                })
+                .collect(),
+            reply,
+        };
+
+        // the op lock is held by this thread until libssh2 returns
+        self.io.set_prompting(true);
+        let responses = match self.requests.send(request) {
+            Ok(()) => response.recv().unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        self.io.set_prompting(false);
+        responses
+    }
+}
+
+pub(crate) fn emit(
+    callback: &Option<AuthCallback>,
+    method: AuthMethod,
+    username: &str,
+    fingerprint: Option<String>,
+    result: &io::Result<()>,
+) {
+    if let Some(callback) = callback {
+        callback(&AuthEvent {
+            method,
+            username,
+            fingerprint,
+            error: result.as_ref().err(),
+        });
+    }
+}
+
+pub(crate) fn fingerprint(blob: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(blob)))
+}
+
+// `ssh-ed25519 AAAA... comment`, as found in a .pub file
+pub(crate) fn fingerprint_openssh(pubkey: &str) -> Option<String> {
+    let blob = pubkey.split_whitespace().nth(1)?;
+    let blob = STANDARD.decode(blob).ok()?;
+
+    Some(fingerprint(&blob))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ssh2::Session;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task;
+
+    use crate::wait;
+
+    // The blocking thread stands in for libssh2 calling the prompt from
+    // inside an authentication attempt, with the op lock held.
+    #[tokio::test(flavor = "current_thread")]
+    async fn ops_park_on_a_prompt_without_blocking_the_prompter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let io = Arc::new(Transport::new(stream));
+        let (requests, mut pending) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut bridge = PromptBridge {
+            requests,
+            io: io.clone(),
+        };
+        let prompting = io.clone();
+        let auth = task::spawn_blocking(move || {
+            let _guard = prompting.lock().unwrap();
+            let prompt = Prompt {
+                text: Cow::Borrowed("Password: "),
+                echo: false,
+            };
+            bridge.prompt("user", "", &[prompt])
+        });
+
+        let request = pending.recv().await.unwrap();
+        let waiting = io.clone();
+        let op = task::spawn(async move {
+            let session = Session::new().unwrap();
+            wait::wait_io(&session, &waiting, || Ok(())).await
+        });
+        // the prompter runs on this thread, it must get its turn
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+        assert!(!op.is_finished());
+
+        request.reply.send(vec!["secret".to_string()]).unwrap();
+        assert_eq!(auth.await.unwrap(), ["secret"]);
+        op.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use crate::transport::peek;
+
+// More than OpenSSH ever sends, and plenty for a login notice.
+const MAX_PRE_VERSION: usize = 8192;
+const FIRST_BYTES: usize = 64;
+
+/// Returned, wrapped in an `io::Error` of kind `InvalidData`, when the peer
+/// doesn't send an `SSH-` version line, like an HTTP server on the wrong port.
+#[derive(Debug, Clone)]
+pub struct NotAnSshServer {
+    /// The start of what the peer sent instead.
+    pub first_bytes: Vec<u8>,
+}
+
+impl fmt::Display for NotAnSshServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "not an SSH server, received \"{}\"",
+            self.first_bytes.escape_ascii()
+        )
+    }
+}
+
+impl Error for NotAnSshServer {}
+
+impl From<NotAnSshServer> for io::Error {
+    fn from(e: NotAnSshServer) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+// RFC 4253 §4.2 lets the server send other lines before its version line,
+// but libssh2 takes the first line it reads for the version. Read those lines
+// off the socket here, leaving the version line for libssh2.
+//
+// `first_bytes` collects the start of what arrived, for the error if the
+// caller gives up waiting.
+pub(crate) async fn skip_to_version(
+    stream: &TcpStream,
+    first_bytes: &mut Vec<u8>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 256];
+    let mut skipped = 0;
+    let mut in_line = false;
+    let mut seen = 0;
+
+    loop {
+        let n = stream
+            .async_io(Interest::READABLE, || {
+                let n = peek(stream, &mut buf)?;
+                // nothing new since the last peek, wait for the next edge
+                if n > 0 && n <= seen {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok(n)
+            })
+            .await?;
+        let data = &buf[..n];
+
+        first_bytes.truncate(skipped.min(FIRST_BYTES));
+        let room = FIRST_BYTES - first_bytes.len();
+        first_bytes.extend_from_slice(&data[..n.min(room)]);
+        if n == 0 {
+            if first_bytes.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before SSH banner",
+                ));
+            }
+            return Err(not_ssh(first_bytes));
+        }
+
+        if !in_line {
+            let k = n.min(4);
+            if data[..k] == b"SSH-"[..k] {
+                if n >= 4 {
+                    return Ok(());
+                }
+                // only part of the version line arrived
+                seen = n;
+                continue;
+            }
+            in_line = true;
+        }
+
+        let take = match data.iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                in_line = false;
+                i + 1
+            }
+            None => n,
+        };
+        consume(stream, take).await?;
+        skipped += take;
+        seen = 0;
+
+        if skipped > MAX_PRE_VERSION {
+            return Err(not_ssh(first_bytes));
+        }
+    }
+}
+
+pub(crate) fn not_ssh(first_bytes: &[u8]) -> io::Error {
+    NotAnSshServer {
+        first_bytes: first_bytes.to_vec(),
+    }
+    .into()
+}
+
+// Drop `n` bytes that were already peeked.
+async fn consume(stream: &TcpStream, mut n: usize) -> io::Result<()> {
+    let mut buf = [0u8; 256];
+    while n > 0 {
+        stream.readable().await?;
+        match stream.try_read(&mut buf[..n.min(256)]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => n -= read,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
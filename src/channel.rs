@@ -1,71 +1,52 @@
+use std::future::{poll_fn, Future};
 use std::io;
 use std::io::{Read, Write};
 use std::mem::MaybeUninit;
-use std::pin::Pin;
+use std::pin::{pin, Pin};
 use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
 
-use ssh2::{
-    BlockDirections, Channel, ExitSignal, ExtendedData, PtyModes, ReadWindow, Session, Stream,
-    WriteWindow,
-};
-use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
-use tokio::net::TcpStream;
+use libssh2_sys as raw;
+use ssh2::{Channel, ExitSignal, ExtendedData, PtyModes, ReadWindow, Session, Stream, WriteWindow};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::runtime::Handle;
+
+use crate::transport::Transport;
+use crate::wait;
+
+const STDERR_LIMIT: usize = 64 * 1024;
 
 pub struct AsyncChannel {
     pub(crate) session: Session,
     pub(crate) channel: Channel,
-    pub(crate) io: Arc<TcpStream>,
+    pub(crate) io: Arc<Transport>,
+    pub(crate) window_size: u32,
+    pub(crate) packet_size: u32,
 }
 
 impl AsyncChannel {
+    // Channels not opened through `channel_open` use the libssh2 defaults.
+    pub(crate) fn new(session: Session, channel: Channel, io: Arc<Transport>) -> Self {
+        AsyncChannel {
+            session,
+            channel,
+            io,
+            window_size: raw::LIBSSH2_CHANNEL_WINDOW_DEFAULT,
+            packet_size: raw::LIBSSH2_CHANNEL_PACKET_DEFAULT,
+        }
+    }
+
     async fn wait_io_mut<R>(
         &mut self,
         mut op: impl FnMut(&mut Channel) -> io::Result<R>,
     ) -> io::Result<R> {
-        loop {
-            match op(&mut self.channel) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let channel = &mut self.channel;
+        wait::wait_io(&self.session, &self.io, || op(channel)).await
     }
 
     async fn wait_io<R>(&self, mut op: impl FnMut(&Channel) -> io::Result<R>) -> io::Result<R> {
-        loop {
-            match op(&self.channel) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let channel = &self.channel;
+        wait::wait_io(&self.session, &self.io, || op(channel)).await
     }
 
     pub async fn setenv(&mut self, var: &str, val: &str) -> io::Result<()> {
@@ -140,6 +121,7 @@ impl AsyncChannel {
 
         Ok(AsyncStream {
             stream,
+            session: self.session.clone(),
             io: self.io.clone(),
         })
     }
@@ -149,6 +131,7 @@ impl AsyncChannel {
 
         Ok(AsyncStream {
             stream,
+            session: self.session.clone(),
             io: self.io.clone(),
         })
     }
@@ -176,6 +159,16 @@ impl AsyncChannel {
         self.wait_io(|channel| Ok(channel.write_window())).await
     }
 
+    /// The initial receive window this channel was opened with.
+    pub fn window_size(&self) -> u32 {
+        self.window_size
+    }
+
+    /// The maximum packet size this channel was opened with.
+    pub fn packet_size(&self) -> u32 {
+        self.packet_size
+    }
+
     pub async fn adjust_receive_window(&mut self, adjust: u64, force: bool) -> io::Result<u64> {
         self.wait_io_mut(|channel| {
             channel
@@ -208,11 +201,239 @@ impl AsyncChannel {
         self.wait_io_mut(|channel| channel.wait_close().map_err(Into::into))
             .await
     }
+
+    /// Run `command` and collect its output, like `std::process::Command::output`.
+    pub async fn output(&mut self, command: &str) -> io::Result<CommandOutput> {
+        self.output_with_stdin(command, &[]).await
+    }
+
+    /// Like [`output`](Self::output), writing `stdin` to the command first.
+    /// stdout and stderr are drained meanwhile, so neither window can stall it.
+    pub async fn output_with_stdin(
+        &mut self,
+        command: &str,
+        stdin: &[u8],
+    ) -> io::Result<CommandOutput> {
+        self.exec(command).await?;
+
+        let mut out = self.channel.stream(0);
+        let mut err = self.channel.stderr();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let (mut out_done, mut err_done) = (false, false);
+        let mut written = 0;
+        let mut eof_sent = false;
+        // Runs until both streams hit EOF. A pass that moves nothing has
+        // libssh2 blocked on the socket in its reads, so `wait_io` waits for
+        // readiness, and fails rather than spin should libssh2 block on
+        // nothing.
+        self.wait_io_mut(|channel| {
+            let mut rechecked = false;
+            loop {
+                let mut progress = false;
+                if !out_done {
+                    progress |= read_available(&mut out, &mut stdout, &mut out_done)?;
+                }
+                if !err_done {
+                    progress |= read_available(&mut err, &mut stderr, &mut err_done)?;
+                }
+                if written < stdin.len() {
+                    match channel.write(&stdin[written..]) {
+                        Ok(n) => {
+                            written += n;
+                            progress = true;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                if written == stdin.len() && !eof_sent {
+                    match channel.send_eof().map_err(io::Error::from) {
+                        Ok(()) => {
+                            eof_sent = true;
+                            progress = true;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                if out_done && err_done {
+                    return Ok(());
+                }
+                if !progress {
+                    // reading one stream can queue up data for the other, that
+                    // won't show up as socket readiness
+                    if !rechecked && channel.read_window().available > 0 {
+                        rechecked = true;
+                        continue;
+                    }
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            }
+        })
+        .await?;
+
+        self.close().await?;
+        self.wait_close().await?;
+
+        let exit_status = self.exit_status().await?;
+        let exit_signal = self.exit_signal().await?.exit_signal;
+
+        Ok(CommandOutput {
+            stdout,
+            stderr,
+            exit_status,
+            exit_signal,
+        })
+    }
+
+    /// Copy between stream 0 and `stream` in both directions until both
+    /// reach EOF, then close the channel. EOF is passed on in each direction
+    /// on its own. Returns the bytes sent to and received from the channel.
+    pub async fn bridge<S>(mut self, stream: S) -> io::Result<(u64, u64)>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (mut local_read, mut local_write) = tokio::io::split(stream);
+        let mut remote_read = self.stream(0)?;
+        let mut remote_write = self.stream(0)?;
+
+        let this = &mut self;
+        let sent = async move {
+            let n = tokio::io::copy(&mut local_read, &mut remote_write).await?;
+            remote_write.flush().await?;
+            this.send_eof().await?;
+            Ok(n)
+        };
+        let received = async {
+            let n = tokio::io::copy(&mut remote_read, &mut local_write).await?;
+            local_write.shutdown().await?;
+            Ok(n)
+        };
+        let res = match try_join(sent, received).await {
+            Ok(res) => res,
+            Err(e) => {
+                // don't leave the remote end waiting on a channel nobody drives
+                let _ = self.send_eof().await;
+                let _ = self.close().await;
+                return Err(e);
+            }
+        };
+
+        self.close().await?;
+        self.wait_close().await?;
+
+        Ok(res)
+    }
+
+    /// Split into owned halves over stream 0 that can be used from separate
+    /// tasks. The channel stays open until both halves are dropped.
+    pub fn into_split(self) -> (ChannelReadHalf, ChannelWriteHalf) {
+        let read = ChannelReadHalf {
+            stream: AsyncStream {
+                stream: self.channel.stream(0),
+                session: self.session.clone(),
+                io: self.io.clone(),
+            },
+            channel: self.channel.clone(),
+        };
+        let write = ChannelWriteHalf {
+            stream: AsyncStream {
+                stream: self.channel.stream(0),
+                session: self.session,
+                io: self.io,
+            },
+            channel: self.channel,
+            eof_sent: false,
+        };
+
+        (read, write)
+    }
+
+    pub fn into_stdout_reader(self) -> ChannelStdoutReader {
+        let stdout = self.channel.stream(0);
+        let stderr = self.channel.stderr();
+
+        ChannelStdoutReader {
+            channel: Some(self),
+            stdout,
+            stderr,
+            stderr_buf: Vec::new(),
+            state: ReaderState::Reading,
+            exit_status: None,
+        }
+    }
+}
+
+/// Output of a command run with [`AsyncChannel::output`].
+///
+/// If the command was killed by a signal, `exit_signal` holds its name and
+/// `exit_status` is meaningless.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_status: i32,
+    pub exit_signal: Option<String>,
+}
+
+// Runs both futures to completion, or until one of them fails.
+async fn try_join<A, B>(
+    a: impl Future<Output = io::Result<A>>,
+    b: impl Future<Output = io::Result<B>>,
+) -> io::Result<(A, B)> {
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    let (mut a_out, mut b_out) = (None, None);
+
+    poll_fn(|cx| {
+        if a_out.is_none() {
+            if let Poll::Ready(res) = a.as_mut().poll(cx) {
+                a_out = Some(res?);
+            }
+        }
+        if b_out.is_none() {
+            if let Poll::Ready(res) = b.as_mut().poll(cx) {
+                b_out = Some(res?);
+            }
+        }
+
+        match (a_out.take(), b_out.take()) {
+            (Some(a), Some(b)) => Poll::Ready(Ok((a, b))),
+            (a, b) => {
+                a_out = a;
+                b_out = b;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+// Reads until `stream` would block, returns whether anything happened.
+fn read_available(stream: &mut Stream, buf: &mut Vec<u8>, done: &mut bool) -> io::Result<bool> {
+    let mut chunk = [0u8; 4096];
+    let mut progress = false;
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                *done = true;
+                return Ok(true);
+            }
+            Ok(r) => {
+                buf.extend_from_slice(&chunk[..r]);
+                progress = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(progress),
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 pub struct AsyncStream {
     stream: Stream,
-    io: Arc<TcpStream>,
+    session: Session,
+    io: Arc<Transport>,
 }
 
 impl AsyncRead for AsyncStream {
@@ -221,29 +442,17 @@ impl AsyncRead for AsyncStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let io = self.io.clone();
-        let stream = &mut self.stream;
-        loop {
-            match io.poll_read_ready(cx)? {
-                Poll::Ready(_) => {
-                    let b = unsafe {
-                        &mut *(buf.unfilled_mut() as *mut [MaybeUninit<u8>] as *mut [u8])
-                    };
-                    match stream.read(b) {
-                        Ok(r) => {
-                            unsafe {
-                                buf.assume_init(r);
-                            }
-                            buf.advance(r);
-                            return Poll::Ready(Ok(()));
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                        Err(e) => return Poll::Ready(Err(e)),
-                    }
-                }
-                Poll::Pending => return Poll::Pending,
-            }
+        let this = &mut *self;
+        let stream = &mut this.stream;
+
+        let b = unsafe { &mut *(buf.unfilled_mut() as *mut [MaybeUninit<u8>] as *mut [u8]) };
+        let r = ready!(wait::poll_io(&this.session, &this.io, cx, || stream.read(b)))?;
+        unsafe {
+            buf.assume_init(r);
         }
+        buf.advance(r);
+
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -253,38 +462,219 @@ impl AsyncWrite for AsyncStream {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        let io = self.io.clone();
-        let stream = &mut self.stream;
+        let this = &mut *self;
+        let stream = &mut this.stream;
 
-        loop {
-            match io.poll_write_ready(cx)? {
-                Poll::Ready(_) => match stream.write(buf) {
-                    Ok(r) => return Poll::Ready(Ok(r)),
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                    Err(e) => return Poll::Ready(Err(e)),
-                },
-                Poll::Pending => return Poll::Pending,
-            };
+        wait::poll_io(&this.session, &this.io, cx, || stream.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let stream = &mut this.stream;
+
+        wait::poll_io(&this.session, &this.io, cx, || stream.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Reading half of [`AsyncChannel::into_split`].
+pub struct ChannelReadHalf {
+    stream: AsyncStream,
+    channel: Channel,
+}
+
+impl ChannelReadHalf {
+    pub fn stderr(&self) -> AsyncStream {
+        AsyncStream {
+            stream: self.channel.stderr(),
+            session: self.stream.session.clone(),
+            io: self.stream.io.clone(),
         }
     }
+}
+
+impl AsyncRead for ChannelReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+/// Writing half of [`AsyncChannel::into_split`], shutting it down sends EOF.
+pub struct ChannelWriteHalf {
+    stream: AsyncStream,
+    channel: Channel,
+    eof_sent: bool,
+}
+
+impl AsyncWrite for ChannelWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        let io = self.io.clone();
-        let stream = &mut self.stream;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
 
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.eof_sent {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+
+        let this = &mut *self;
+        let channel = &mut this.channel;
+        ready!(wait::poll_io(
+            &this.stream.session,
+            &this.stream.io,
+            cx,
+            || { channel.send_eof().map_err(Into::into) }
+        ))?;
+        this.eof_sent = true;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReaderState {
+    Reading,
+    Closing,
+    WaitClose,
+    Done,
+}
+
+/// Owned reader over the stdout of a channel, see [`AsyncChannel::into_stdout_reader`].
+///
+/// stderr is drained while reading so the remote command can't stall on a full
+/// extended data window; only the first 64 KiB of it are kept. Once stdout hits
+/// EOF the channel is closed and the exit status becomes available. Dropping the
+/// reader before that closes the channel in the background.
+pub struct ChannelStdoutReader {
+    channel: Option<AsyncChannel>,
+    stdout: Stream,
+    stderr: Stream,
+    stderr_buf: Vec<u8>,
+    state: ReaderState,
+    exit_status: Option<i32>,
+}
+
+impl ChannelStdoutReader {
+    pub fn stderr(&self) -> &[u8] {
+        &self.stderr_buf
+    }
+
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+}
+
+fn drain_stderr(stderr: &mut Stream, stderr_buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        match stderr.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(r) => {
+                let keep = r.min(STDERR_LIMIT - stderr_buf.len());
+                stderr_buf.extend_from_slice(&buf[..keep]);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl AsyncRead for ChannelStdoutReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // a zero-length read would look like EOF to the state machine and
+        // start closing the channel
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let this = &mut *self;
+        let channel = match this.channel.as_mut() {
+            Some(channel) => channel,
+            None => return Poll::Ready(Ok(())),
+        };
+        let io = channel.io.clone();
+        let session = channel.session.clone();
+        let stdout = &mut this.stdout;
+        let stderr = &mut this.stderr;
+        let stderr_buf = &mut this.stderr_buf;
+
+        let b = unsafe { &mut *(buf.unfilled_mut() as *mut [MaybeUninit<u8>] as *mut [u8]) };
         loop {
-            match io.poll_write_ready(cx)? {
-                Poll::Ready(_) => match stream.flush() {
-                    Ok(r) => return Poll::Ready(Ok(r)),
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                    Err(e) => return Poll::Ready(Err(e)),
-                },
-                Poll::Pending => return Poll::Pending,
-            };
+            let state = this.state;
+            if state == ReaderState::Done {
+                return Poll::Ready(Ok(()));
+            }
+
+            let r = ready!(wait::poll_io(&session, &io, cx, || {
+                drain_stderr(stderr, stderr_buf)?;
+                match state {
+                    ReaderState::Reading => stdout.read(b),
+                    ReaderState::Closing => channel.channel.close().map(|_| 0).map_err(Into::into),
+                    ReaderState::WaitClose => {
+                        channel.channel.wait_close().map(|_| 0).map_err(Into::into)
+                    }
+                    ReaderState::Done => Ok(0),
+                }
+            }))?;
+
+            match state {
+                ReaderState::Reading if r > 0 => {
+                    unsafe {
+                        buf.assume_init(r);
+                    }
+                    buf.advance(r);
+                    return Poll::Ready(Ok(()));
+                }
+                ReaderState::Reading => this.state = ReaderState::Closing,
+                ReaderState::Closing => this.state = ReaderState::WaitClose,
+                ReaderState::WaitClose => {
+                    this.exit_status = channel.channel.exit_status().ok();
+                    this.state = ReaderState::Done;
+                }
+                ReaderState::Done => {}
+            }
         }
     }
+}
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.poll_flush(cx)
+impl Drop for ChannelStdoutReader {
+    fn drop(&mut self) {
+        if self.state == ReaderState::Done {
+            return;
+        }
+
+        if let Some(mut channel) = self.channel.take() {
+            match Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move {
+                        let _ = channel.close().await;
+                    });
+                }
+                Err(_) => {
+                    if let Ok(_guard) = channel.io.lock() {
+                        let _ = channel.channel.close();
+                    }
+                }
+            }
+        }
     }
 }
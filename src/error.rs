@@ -0,0 +1,145 @@
+use std::io;
+use std::os::raw::c_int;
+
+use libssh2_sys as raw;
+use ssh2::ErrorCode;
+
+// Like ssh2's `From<Error> for io::Error`, but with a kind for every SFTP
+// status that has one, and the ssh2 error kept as the source so its code can
+// still be recovered with `get_ref` and `downcast_ref`.
+pub(crate) fn from_ssh2(e: ssh2::Error) -> io::Error {
+    io::Error::new(kind(e.code()), e)
+}
+
+// `Read` and `Write` on an ssh2 `File` convert the error themselves, keeping
+// only the message ssh2 has for its code. Those messages come from ssh2's own
+// per-status table, not from the server, so looking the message up in that
+// table gives back the SFTP status, which then maps like any other ssh2 error.
+pub(crate) fn from_io(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::WouldBlock {
+        return e;
+    }
+
+    match sftp_status(&e) {
+        Some(status) => from_ssh2(ssh2::Error::from_errno(ErrorCode::SFTP(status))),
+        None => e,
+    }
+}
+
+fn sftp_status(e: &io::Error) -> Option<c_int> {
+    let msg = e.get_ref()?.to_string();
+    (raw::LIBSSH2_FX_EOF..=raw::LIBSSH2_FX_LINK_LOOP)
+        .find(|&status| ssh2::Error::from_errno(ErrorCode::SFTP(status)).message() == msg)
+}
+
+fn kind(code: ErrorCode) -> io::ErrorKind {
+    match code {
+        ErrorCode::Session(raw::LIBSSH2_ERROR_EAGAIN) => io::ErrorKind::WouldBlock,
+        ErrorCode::Session(raw::LIBSSH2_ERROR_TIMEOUT) => io::ErrorKind::TimedOut,
+        ErrorCode::SFTP(status) => sftp_kind(status),
+        ErrorCode::Session(_) => io::ErrorKind::Other,
+    }
+}
+
+// NO_CONNECTION, CONNECTION_LOST and EOF stay `Other`. Their kinds would mark
+// the whole session as lost, while they only concern the SFTP subsystem.
+fn sftp_kind(status: c_int) -> io::ErrorKind {
+    match status {
+        raw::LIBSSH2_FX_NO_SUCH_FILE | raw::LIBSSH2_FX_NO_SUCH_PATH => io::ErrorKind::NotFound,
+        raw::LIBSSH2_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        raw::LIBSSH2_FX_FILE_ALREADY_EXISTS => io::ErrorKind::AlreadyExists,
+        raw::LIBSSH2_FX_WRITE_PROTECT => io::ErrorKind::ReadOnlyFilesystem,
+        raw::LIBSSH2_FX_NO_SPACE_ON_FILESYSTEM | raw::LIBSSH2_FX_QUOTA_EXCEEDED => {
+            io::ErrorKind::StorageFull
+        }
+        raw::LIBSSH2_FX_DIR_NOT_EMPTY => io::ErrorKind::DirectoryNotEmpty,
+        raw::LIBSSH2_FX_NOT_A_DIRECTORY => io::ErrorKind::NotADirectory,
+        raw::LIBSSH2_FX_OP_UNSUPPORTED => io::ErrorKind::Unsupported,
+        raw::LIBSSH2_FX_LOCK_CONFLICT => io::ErrorKind::ResourceBusy,
+        raw::LIBSSH2_FX_INVALID_HANDLE | raw::LIBSSH2_FX_INVALID_FILENAME => {
+            io::ErrorKind::InvalidInput
+        }
+        raw::LIBSSH2_FX_BAD_MESSAGE => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sftp(status: c_int) -> io::Error {
+        from_ssh2(ssh2::Error::from_errno(ErrorCode::SFTP(status)))
+    }
+
+    #[test]
+    fn sftp_statuses_map_to_kinds() {
+        let cases = [
+            (raw::LIBSSH2_FX_NO_SUCH_FILE, io::ErrorKind::NotFound),
+            (raw::LIBSSH2_FX_NO_SUCH_PATH, io::ErrorKind::NotFound),
+            (
+                raw::LIBSSH2_FX_PERMISSION_DENIED,
+                io::ErrorKind::PermissionDenied,
+            ),
+            (
+                raw::LIBSSH2_FX_FILE_ALREADY_EXISTS,
+                io::ErrorKind::AlreadyExists,
+            ),
+            (
+                raw::LIBSSH2_FX_NO_SPACE_ON_FILESYSTEM,
+                io::ErrorKind::StorageFull,
+            ),
+            (raw::LIBSSH2_FX_QUOTA_EXCEEDED, io::ErrorKind::StorageFull),
+            (
+                raw::LIBSSH2_FX_DIR_NOT_EMPTY,
+                io::ErrorKind::DirectoryNotEmpty,
+            ),
+            (raw::LIBSSH2_FX_OP_UNSUPPORTED, io::ErrorKind::Unsupported),
+            // would mark the session as lost
+            (raw::LIBSSH2_FX_CONNECTION_LOST, io::ErrorKind::Other),
+        ];
+
+        for (status, kind) in cases {
+            assert_eq!(sftp(status).kind(), kind, "status {}", status);
+        }
+    }
+
+    #[test]
+    fn ssh2_error_is_kept_as_the_source() {
+        let e = sftp(raw::LIBSSH2_FX_PERMISSION_DENIED);
+        let source = e.get_ref().unwrap().downcast_ref::<ssh2::Error>().unwrap();
+        assert_eq!(
+            source.code(),
+            ErrorCode::SFTP(raw::LIBSSH2_FX_PERMISSION_DENIED)
+        );
+
+        let e = from_ssh2(ssh2::Error::from_errno(ErrorCode::Session(
+            raw::LIBSSH2_ERROR_EAGAIN,
+        )));
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    // What ssh2's `File` hands back from `write` when the server is out of space.
+    #[test]
+    fn file_write_errors_get_their_sftp_status_back() {
+        for status in [
+            raw::LIBSSH2_FX_NO_SPACE_ON_FILESYSTEM,
+            raw::LIBSSH2_FX_QUOTA_EXCEEDED,
+        ] {
+            let e = io::Error::from(ssh2::Error::from_errno(ErrorCode::SFTP(status)));
+            assert_eq!(e.kind(), io::ErrorKind::Other);
+
+            let e = from_io(e);
+            assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+            let source = e.get_ref().unwrap().downcast_ref::<ssh2::Error>().unwrap();
+            assert_eq!(source.code(), ErrorCode::SFTP(status));
+        }
+
+        // anything that isn't one of ssh2's status messages is left alone
+        let e = from_io(io::Error::other("Failure"));
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert!(e.get_ref().unwrap().downcast_ref::<ssh2::Error>().is_none());
+        let e = from_io(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
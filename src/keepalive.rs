@@ -0,0 +1,65 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh2::Session;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::transport::Transport;
+use crate::wait;
+
+/// Handle to the task started by
+/// [`AsyncSession::spawn_keepalive`](crate::AsyncSession::spawn_keepalive).
+///
+/// The task is stopped when the handle is dropped. If sending a keepalive
+/// fails, the task ends and the error is kept here.
+pub struct KeepaliveHandle {
+    task: JoinHandle<()>,
+    error: watch::Receiver<Option<Arc<io::Error>>>,
+}
+
+impl KeepaliveHandle {
+    pub(crate) fn spawn(session: Session, io: Arc<Transport>) -> Self {
+        let (tx, error) = watch::channel(None);
+        let task = tokio::spawn(async move {
+            loop {
+                let res = wait::wait_io(&session, &io, || {
+                    session.keepalive_send().map_err(Into::into)
+                })
+                .await;
+
+                match res {
+                    // 0 if keepalives aren't configured, check again later
+                    Ok(secs) => time::sleep(Duration::from_secs(secs.max(1).into())).await,
+                    Err(e) => {
+                        let _ = tx.send(Some(Arc::new(e)));
+                        return;
+                    }
+                }
+            }
+        });
+
+        KeepaliveHandle { task, error }
+    }
+
+    /// The error that stopped the keepalive task, if any.
+    pub fn error(&self) -> Option<Arc<io::Error>> {
+        self.error.borrow().clone()
+    }
+
+    /// Wait until sending a keepalive fails.
+    pub async fn failed(&mut self) -> Arc<io::Error> {
+        match self.error.wait_for(Option::is_some).await {
+            Ok(error) => error.clone().unwrap(),
+            Err(_) => Arc::new(io::Error::other("keepalive task ended unexpectedly")),
+        }
+    }
+}
+
+impl Drop for KeepaliveHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
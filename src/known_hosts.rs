@@ -0,0 +1,264 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHosts};
+
+use crate::error;
+
+/// The key a server presented, see [`HostKeyPolicy::Callback`].
+///
+/// `fingerprint` is the OpenSSH style `SHA256:...` fingerprint of `key`.
+#[derive(Debug, Clone)]
+pub struct HostKeyInfo {
+    pub key_type: HostKeyType,
+    pub key: Vec<u8>,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyDecision {
+    Accept,
+    /// Accept the key and append it to the known_hosts file.
+    AcceptAndSave,
+    Reject,
+}
+
+/// What [`AsyncSession::verify_host_key`](crate::AsyncSession::verify_host_key)
+/// does with a host that isn't in the known_hosts file yet. A key that differs
+/// from the recorded one, or that an `@revoked` line lists for the host, is
+/// always rejected with [`HostKeyMismatch`].
+pub enum HostKeyPolicy {
+    Strict,
+    AcceptNew { write_back: bool },
+    Callback(Box<dyn FnMut(&HostKeyInfo) -> HostKeyDecision + Send>),
+}
+
+/// Returned, wrapped in an `io::Error` of kind `PermissionDenied`, when the
+/// known_hosts file has a different key for the host.
+///
+/// This is what a man-in-the-middle attack looks like, so it is worth telling
+/// apart from other failures with `io::Error::get_ref` and `downcast_ref`.
+#[derive(Debug, Clone)]
+pub struct HostKeyMismatch {
+    pub host: String,
+    pub fingerprint: String,
+}
+
+impl fmt::Display for HostKeyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "host key for {} has changed to {}, it may be a man-in-the-middle attack",
+            self.host, self.fingerprint
+        )
+    }
+}
+
+impl Error for HostKeyMismatch {}
+
+pub(crate) fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+
+    Some(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+// How OpenSSH names a host in known_hosts.
+pub(crate) fn host_pattern(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+// `|1|salt|hmac-sha1(salt, host) keytype key`, as written with HashKnownHosts.
+pub(crate) fn hashed_entry(host: &str, port: u16, key: &[u8]) -> io::Result<String> {
+    let mut salt = [0u8; 20];
+    getrandom::getrandom(&mut salt)?;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(&salt).map_err(io::Error::other)?;
+    mac.update(host_pattern(host, port).as_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    Ok(format!(
+        "|1|{}|{} {} {}\n",
+        STANDARD.encode(salt),
+        STANDARD.encode(hash),
+        key_name(key)?,
+        STANDARD.encode(key)
+    ))
+}
+
+// The key blob starts with its type as an SSH string.
+fn key_name(key: &[u8]) -> io::Result<&str> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed host key");
+
+    let len = key.get(..4).ok_or_else(invalid)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let name = key.get(4..4 + len).ok_or_else(invalid)?;
+
+    std::str::from_utf8(name).map_err(|_| invalid())
+}
+
+// libssh2 is only told the key, not its type, so it compares against whatever
+// entry for the host comes first. A host with an ed25519 and an rsa entry
+// would then fail with a mismatch half the time, so only load the lines
+// with the same key type as the server's.
+//
+// libssh2 doesn't know `@revoked` either. Those lines are checked on their
+// own first, and a key they match is reported as a mismatch so that no
+// policy ends up accepting, let alone saving, it.
+pub(crate) fn check(
+    known_hosts: &mut KnownHosts,
+    contents: &str,
+    host: &str,
+    port: u16,
+    key: &[u8],
+) -> io::Result<CheckResult> {
+    let name = key_name(key)?;
+    let mut revoked = Vec::new();
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        let (list, line) = match line.trim_start().strip_prefix("@revoked") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => (&mut revoked, rest),
+            _ => (&mut entries, line),
+        };
+        let mut fields = line.split_whitespace();
+        // other markers like @cert-authority aren't understood by libssh2
+        let key_type = match fields.next() {
+            Some(hosts) if !hosts.starts_with('#') && !hosts.starts_with('@') => fields.next(),
+            _ => None,
+        };
+        if key_type == Some(name) {
+            list.push(line);
+        }
+    }
+
+    if !revoked.is_empty() {
+        let loaded = known_hosts.iter().map_err(error::from_ssh2)?.len();
+        for line in &revoked {
+            let _ = known_hosts.read_str(line, KnownHostFileKind::OpenSSH);
+        }
+        let res = known_hosts.check_port(host, port, key);
+        for entry in known_hosts
+            .iter()
+            .map_err(error::from_ssh2)?
+            .iter()
+            .skip(loaded)
+        {
+            known_hosts.remove(entry).map_err(error::from_ssh2)?;
+        }
+        if let CheckResult::Match = res {
+            return Ok(CheckResult::Mismatch);
+        }
+    }
+
+    for line in entries {
+        let _ = known_hosts.read_str(line, KnownHostFileKind::OpenSSH);
+    }
+
+    Ok(known_hosts.check_port(host, port, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ssh2::Session;
+
+    // Only the type is looked at, the rest doesn't have to be a real key.
+    fn key(name: &str, fill: u8) -> Vec<u8> {
+        let mut key = (name.len() as u32).to_be_bytes().to_vec();
+        key.extend_from_slice(name.as_bytes());
+        key.extend_from_slice(&[0, 0, 0, 32]);
+        key.extend_from_slice(&[fill; 32]);
+        key
+    }
+
+    fn line(host: &str, key: &[u8]) -> String {
+        format!(
+            "{} {} {}\n",
+            host,
+            key_name(key).unwrap(),
+            STANDARD.encode(key)
+        )
+    }
+
+    fn check_str(contents: &str, port: u16, key: &[u8]) -> CheckResult {
+        let mut known_hosts = Session::new().unwrap().known_hosts().unwrap();
+        check(&mut known_hosts, contents, "example.com", port, key).unwrap()
+    }
+
+    #[test]
+    fn only_entries_of_the_same_key_type_are_compared() {
+        let ed25519 = key("ssh-ed25519", 1);
+        let rsa = key("ssh-rsa", 2);
+        let contents = format!(
+            "# comment\n@cert-authority *.example.com {}{}",
+            line("example.com", &rsa).trim_start_matches("example.com "),
+            line("example.com", &rsa)
+        );
+
+        assert!(matches!(check_str(&contents, 22, &rsa), CheckResult::Match));
+        assert!(matches!(
+            check_str(&contents, 22, &key("ssh-rsa", 3)),
+            CheckResult::Mismatch
+        ));
+        // an rsa entry says nothing about the host's ed25519 key
+        assert!(matches!(
+            check_str(&contents, 22, &ed25519),
+            CheckResult::NotFound
+        ));
+
+        let contents = format!("{}{}", contents, line("example.com", &ed25519));
+        assert!(matches!(
+            check_str(&contents, 22, &ed25519),
+            CheckResult::Match
+        ));
+        assert!(matches!(check_str(&contents, 22, &rsa), CheckResult::Match));
+    }
+
+    #[test]
+    fn hashed_entries_name_the_port() {
+        let key = key("ssh-ed25519", 1);
+        let entry = hashed_entry("example.com", 2222, &key).unwrap();
+
+        assert!(matches!(check_str(&entry, 2222, &key), CheckResult::Match));
+        assert!(matches!(check_str(&entry, 22, &key), CheckResult::NotFound));
+    }
+
+    #[test]
+    fn revoked_keys_are_a_mismatch() {
+        let revoked = key("ssh-ed25519", 1);
+        let other = key("ssh-ed25519", 2);
+        let contents = format!("@revoked {}", line("*.example.com,example.com", &revoked));
+
+        assert!(matches!(
+            check_str(&contents, 22, &revoked),
+            CheckResult::Mismatch
+        ));
+        // revoking a key doesn't make up an entry for any other key
+        assert!(matches!(
+            check_str(&contents, 22, &other),
+            CheckResult::NotFound
+        ));
+
+        // a revoked key stays revoked even if it is also listed as valid
+        let contents = format!("{}{}", line("example.com", &revoked), contents);
+        assert!(matches!(
+            check_str(&contents, 22, &revoked),
+            CheckResult::Mismatch
+        ));
+        let contents = format!("{}{}", contents, line("example.com", &other));
+        assert!(matches!(
+            check_str(&contents, 22, &other),
+            CheckResult::Match
+        ));
+    }
+}
@@ -1,11 +1,27 @@
 pub use agent::AsyncAgent;
-pub use channel::{AsyncChannel, AsyncStream};
+pub use auth::{AsyncKeyboardInteractivePrompt, AuthEvent, AuthMethod};
+pub use banner::NotAnSshServer;
+pub use channel::{
+    AsyncChannel, AsyncStream, ChannelReadHalf, ChannelStdoutReader, ChannelWriteHalf,
+    CommandOutput,
+};
+pub use keepalive::KeepaliveHandle;
+pub use known_hosts::{HostKeyDecision, HostKeyInfo, HostKeyMismatch, HostKeyPolicy};
 pub use listener::AsyncListener;
 pub use session::AsyncSession;
-pub use sftp::{AsyncSftp, AsyncFile};
+pub use sftp::{AsyncFile, AsyncSftp, ReadDir, TransferError, TransferOptions};
+pub use transport::ConnectionLost;
 
 mod agent;
+mod auth;
+mod banner;
 mod channel;
+mod error;
+mod keepalive;
+mod known_hosts;
 mod listener;
+mod proxy;
 mod session;
 mod sftp;
+mod transport;
+mod wait;
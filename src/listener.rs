@@ -1,54 +1,119 @@
+use std::collections::VecDeque;
+use std::future::poll_fn;
 use std::io;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 
-use ssh2::{BlockDirections, Listener, Session};
-use tokio::io::Interest;
-use tokio::net::TcpStream;
+use futures_core::Stream;
+use ssh2::{Channel, Listener, Session};
 
+use crate::transport::Transport;
+use crate::wait;
 use crate::AsyncChannel;
 
+/// A remote port forward, see [`AsyncSession::channel_forward_listen`](crate::AsyncSession::channel_forward_listen).
+///
+/// Also a `Stream` of incoming channels, which ends once the listener is
+/// closed and the channels queued up to then have been handed out.
+///
+/// Dropping it without [`close`](Self::close) cancels the forward only if
+/// the socket takes the request right away.
 pub struct AsyncListener {
-    pub(crate) listener: Listener,
-    pub(crate) session: Session,
-    pub(crate) io: Arc<TcpStream>,
+    listener: Option<Listener>,
+    session: Session,
+    io: Arc<Transport>,
+    // accepted during `close`, libssh2 would drop them with the listener
+    drained: VecDeque<Channel>,
 }
 
 impl AsyncListener {
-    async fn wait_io_mut<R>(
-        &mut self,
-        mut op: impl FnMut(&mut Listener) -> io::Result<R>,
-    ) -> io::Result<R> {
-        loop {
-            match op(&mut self.listener) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
+    pub(crate) fn new(session: Session, listener: Listener, io: Arc<Transport>) -> Self {
+        AsyncListener {
+            listener: Some(listener),
+            session,
+            io,
+            drained: VecDeque::new(),
         }
     }
 
+    fn channel(&self, channel: Channel) -> AsyncChannel {
+        AsyncChannel::new(self.session.clone(), channel, self.io.clone())
+    }
+
+    /// Wait for the next forwarded connection.
+    ///
+    /// Cancel-safe: a channel is only taken from libssh2's queue when it is
+    /// returned right away.
     pub async fn accept(&mut self) -> io::Result<AsyncChannel> {
-        let channel = self
-            .wait_io_mut(|listener| listener.accept().map_err(Into::into))
-            .await?;
-
-        Ok(AsyncChannel {
-            channel,
-            session: self.session.clone(),
-            io: self.io.clone(),
+        let listener = match self.listener.as_mut() {
+            Some(listener) => listener,
+            None => {
+                return match self.drained.pop_front() {
+                    Some(channel) => Ok(self.channel(channel)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "listener is closed",
+                    )),
+                }
+            }
+        };
+
+        let channel = wait::wait_io(&self.session, &self.io, || {
+            listener.accept().map_err(Into::into)
         })
+        .await?;
+
+        Ok(self.channel(channel))
+    }
+
+    /// Stop the remote side from forwarding new connections.
+    ///
+    /// Connections that were already queued can still be accepted afterwards.
+    pub async fn close(&mut self) -> io::Result<()> {
+        let mut listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+
+        // a prompt in progress would cut the draining short
+        poll_fn(|cx| self.io.poll_prompt_done(cx)).await;
+        while let Ok(Ok(channel)) = wait::attempt(&self.session, &self.io, || {
+            listener.accept().map_err(Into::into)
+        }) {
+            self.drained.push_back(channel);
+        }
+
+        // ssh2 only sends the cancel request when the listener is dropped, and
+        // doesn't retry it if the socket is full, so wait until it isn't
+        poll_fn(|cx| self.io.poll_write_ready(cx))
+            .await
+            .map_err(|e| self.io.fail(&self.session, e))?;
+        self.io.check()?;
+
+        let _guard = self.io.lock_after_prompt().await;
+        drop(listener);
+        Ok(())
+    }
+}
+
+impl Stream for AsyncListener {
+    type Item = io::Result<AsyncChannel>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let listener = match this.listener.as_mut() {
+            Some(listener) => listener,
+            None => {
+                let channel = this.drained.pop_front();
+                return Poll::Ready(channel.map(|channel| Ok(this.channel(channel))));
+            }
+        };
+
+        let res = ready!(wait::poll_io(&this.session, &this.io, cx, || {
+            listener.accept().map_err(Into::into)
+        }));
+
+        Poll::Ready(Some(res.map(|channel| this.channel(channel))))
     }
 }
@@ -0,0 +1,39 @@
+use std::io;
+use std::net::Ipv4Addr;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// libssh2 needs a socket to talk to. A connected pair of loopback sockets is
+// the portable way to get one for a transport that isn't a socket itself.
+pub(crate) async fn socket_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let local = TcpStream::connect(listener.local_addr()?).await?;
+
+    loop {
+        let (remote, peer) = listener.accept().await?;
+        // anyone on this host could have connected first
+        if peer == local.local_addr()? {
+            return Ok((local, remote));
+        }
+    }
+}
+
+// Copies between the transport and our end of the socket pair until either
+// side is done, passing EOF on in both directions.
+pub(crate) fn spawn_pump<R, W>(mut reader: R, mut writer: W, stream: TcpStream)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut stream_read, mut stream_write) = stream.into_split();
+
+    tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut reader, &mut stream_write).await;
+        let _ = stream_write.shutdown().await;
+    });
+    tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut stream_read, &mut writer).await;
+        let _ = writer.shutdown().await;
+    });
+}
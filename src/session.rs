@@ -1,107 +1,235 @@
 use std::io;
 use std::net::TcpStream as StdTcpStream;
-#[cfg(unix)]
-use std::os::unix::io::{AsRawFd, FromRawFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
+use libssh2_sys as raw;
 use ssh2::{
-    BlockDirections, DisconnectCode, HashType, HostKeyType, KeyboardInteractivePrompt, KnownHosts,
+    CheckResult, DisconnectCode, HashType, HostKeyType, KeyboardInteractivePrompt, KnownHosts,
     MethodType, ScpFileStat, Session,
 };
-use tokio::io::Interest;
-use tokio::net::TcpStream;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Ready};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time;
 
 use crate::agent::AsyncAgent;
+use crate::auth::{
+    self, AsyncKeyboardInteractivePrompt, AuthCallback, AuthEvent, AuthMethod, PromptBridge,
+};
+use crate::banner;
 use crate::channel::AsyncChannel;
+use crate::error;
+use crate::keepalive::KeepaliveHandle;
+use crate::known_hosts::{self, HostKeyDecision, HostKeyInfo, HostKeyMismatch, HostKeyPolicy};
+use crate::proxy;
 use crate::sftp::AsyncSftp;
+use crate::transport::{SessionStream, Transport};
+use crate::wait;
 use crate::AsyncListener;
 
+const DEFAULT_BANNER_TIMEOUT: Duration = Duration::from_secs(15);
+const MIN_PACKET_SIZE: u32 = 1024;
+const MAX_PACKET_SIZE: u32 = raw::LIBSSH2_CHANNEL_PACKET_DEFAULT;
+
+// libssh2 skips the names it wasn't built with
+const MODERN_KEX_PREFS: &str = "curve25519-sha256,curve25519-sha256@libssh.org,\
+    ecdh-sha2-nistp256,ecdh-sha2-nistp384,ecdh-sha2-nistp521,\
+    diffie-hellman-group-exchange-sha256,diffie-hellman-group16-sha512,\
+    diffie-hellman-group18-sha512,diffie-hellman-group14-sha256,\
+    diffie-hellman-group14-sha1,diffie-hellman-group1-sha1,\
+    diffie-hellman-group-exchange-sha1";
+const LEGACY_KEX_PREFS: &str = "diffie-hellman-group14-sha1,diffie-hellman-group1-sha1,\
+    diffie-hellman-group-exchange-sha1,diffie-hellman-group14-sha256,\
+    diffie-hellman-group-exchange-sha256,ecdh-sha2-nistp256,curve25519-sha256";
+const LEGACY_HOSTKEY_PREFS: &str = "ssh-rsa,ssh-dss,rsa-sha2-256,rsa-sha2-512,\
+    ecdsa-sha2-nistp256,ssh-ed25519";
+const LEGACY_CRYPT_PREFS: &str = "aes128-cbc,aes256-cbc,3des-cbc,aes128-ctr,aes256-ctr";
+
 pub struct AsyncSession {
     session: Session,
-    io: Arc<TcpStream>,
+    io: Arc<Transport>,
+    banner_timeout: Duration,
+    banner_checked: bool,
+    known_hosts_file: Option<PathBuf>,
+    auth_event: Option<AuthCallback>,
 }
 
 impl AsyncSession {
     pub fn new(stream: StdTcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        let io = Arc::new(Transport::new(TcpStream::from_std(stream)?));
+
         let mut session = Session::new()?;
         session.set_blocking(false);
-        session.set_tcp_stream(stream);
-
-        let fd = session.as_raw_fd();
-        let stream = unsafe { StdTcpStream::from_raw_fd(fd) };
-        let stream = Arc::new(TcpStream::from_std(stream)?);
+        session.set_tcp_stream(SessionStream(io.clone()));
 
         Ok(AsyncSession {
             session,
-            io: stream,
+            io,
+            banner_timeout: DEFAULT_BANNER_TIMEOUT,
+            banner_checked: false,
+            known_hosts_file: known_hosts::default_path(),
+            auth_event: None,
         })
     }
 
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        Self::from_tokio_stream(stream).await
+    }
+
+    pub async fn from_tokio_stream(stream: TcpStream) -> io::Result<Self> {
+        let mut session = Self::new(stream.into_std()?)?;
+        session.handshake().await?;
+
+        Ok(session)
+    }
+
+    /// Create a session over any byte stream, like a channel to another host.
+    ///
+    /// The stream is bridged to the socket libssh2 needs by a background task,
+    /// so this must be called from within a tokio runtime. Closing the stream
+    /// ends the session like a dropped connection would.
+    pub async fn new_over<T>(transport: T) -> io::Result<Self>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(transport);
+        let (stream, pump) = proxy::socket_pair().await?;
+        proxy::spawn_pump(reader, writer, pump);
+
+        Self::new(stream.into_std()?)
+    }
+
+    /// Connect to `host` through `jump`, like OpenSSH's `ProxyJump`, and
+    /// complete the handshake. Authentication is left to the caller.
+    pub async fn connect_via(jump: &AsyncSession, host: &str, port: u16) -> io::Result<Self> {
+        let channel = jump.channel_direct_tcpip(host, port, None).await?;
+        let (reader, writer) = channel.into_split();
+        let (stream, pump) = proxy::socket_pair().await?;
+        proxy::spawn_pump(reader, writer, pump);
+
+        let mut session = Self::new(stream.into_std()?)?;
+        session.handshake().await?;
+
+        Ok(session)
+    }
+
     async fn wait_io_mut<R>(
         &mut self,
         mut op: impl FnMut(&mut Session) -> io::Result<R>,
     ) -> io::Result<R> {
-        loop {
-            match op(&mut self.session) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let session = self.session.clone();
+        let inner = &mut self.session;
+        wait::wait_io(&session, &self.io, || op(inner)).await
     }
 
     async fn wait_io<'a, R: 'a>(
         &'a self,
         mut op: impl FnMut(&'a Session) -> io::Result<R>,
     ) -> io::Result<R> {
-        loop {
-            match op(&self.session) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
+        let session = &self.session;
+        wait::wait_io(session, &self.io, || op(session)).await
+    }
+
+    /// Poll until the socket is ready in the direction libssh2 last blocked on.
+    ///
+    /// This doesn't consume any data, so it is cancel-safe and may be called
+    /// repeatedly, also from several tasks at once. Returns `Ready::EMPTY`
+    /// straight away if libssh2 isn't blocked on the socket, or once a
+    /// keyboard-interactive prompt holding the session has been answered.
+    ///
+    /// Readiness an operation on the session fails to make use of is cleared
+    /// by that operation, so after it returned `Pending` this waits for the
+    /// socket again instead of reporting it ready over and over.
+    pub fn poll_io_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<Ready>> {
+        wait::poll_ready(&self.session, &self.io, cx)
+    }
+
+    // A handshake retried after a timeout finds the banner already consumed
+    // by libssh2, so the check only runs until it passed once.
+    async fn check_banner(&mut self) -> io::Result<()> {
+        if self.banner_checked {
+            return Ok(());
+        }
+
+        let timeout = match self.io.op_timeout() {
+            Some(op_timeout) => op_timeout.min(self.banner_timeout),
+            None => self.banner_timeout,
+        };
+        let mut first_bytes = Vec::new();
+        let skip = banner::skip_to_version(self.io.stream(), &mut first_bytes);
+        match time::timeout(timeout, skip).await {
+            Ok(res) => res?,
+            Err(_) if first_bytes.is_empty() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no SSH banner received from server",
+                ))
             }
+            Err(_) => return Err(banner::not_ssh(&first_bytes)),
         }
+
+        self.banner_checked = true;
+        Ok(())
+    }
+
+    /// Set how long `handshake` waits for the server's `SSH-` banner, defaults to 15 seconds.
+    pub fn set_banner_timeout(&mut self, timeout: Duration) {
+        self.banner_timeout = timeout;
+    }
+
+    /// Limit how long any async method of this session, and of the channels,
+    /// sftp handles and listeners derived from it, waits on the server. Calls
+    /// running over fail with `TimedOut`. Zero, the default, waits forever.
+    ///
+    /// libssh2 keeps the state of a call that would block, so a timed out
+    /// call can be retried, and other calls, like a new channel, work as
+    /// usual. A key exchange the server started is picked up again by the
+    /// next call. Only a timed out [`handshake`](Self::handshake) leaves the
+    /// session unusable: it and every later call fail with a
+    /// [`ConnectionLost`](crate::ConnectionLost) wrapping the `TimedOut`
+    /// error. Drop the session and connect again.
+    ///
+    /// The `AsyncRead`/`AsyncWrite` impls aren't covered, wrap those in
+    /// `tokio::time::timeout` as usual. libssh2's own `Session::set_timeout`
+    /// only applies in blocking mode, which this session never runs in.
+    pub fn set_op_timeout(&self, timeout: Duration) {
+        self.io.set_op_timeout(timeout);
     }
 
     pub async fn handshake(&mut self) -> io::Result<()> {
-        self.wait_io_mut(|session| session.handshake().map_err(Into::into))
-            .await
+        self.check_banner().await?;
+
+        let res = self
+            .wait_io_mut(|session| session.handshake().map_err(Into::into))
+            .await;
+        match res {
+            // the server is left halfway through the key exchange
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(self.io.poison(e)),
+            res => res,
+        }
     }
 
     pub async fn userauth_password(&self, username: &str, password: &str) -> io::Result<()> {
-        self.wait_io(|session| {
-            session
-                .userauth_password(username, password)
-                .map_err(Into::into)
-        })
-        .await
+        let res = self
+            .wait_io(|session| {
+                session
+                    .userauth_password(username, password)
+                    .map_err(Into::into)
+            })
+            .await;
+
+        auth::emit(&self.auth_event, AuthMethod::Password, username, None, &res);
+        res
     }
 
     pub async fn userauth_keyboard_interactive<P: KeyboardInteractivePrompt>(
@@ -109,17 +237,97 @@ impl AsyncSession {
         username: &str,
         prompter: &mut P,
     ) -> io::Result<()> {
-        self.wait_io(|session| {
-            session
-                .userauth_keyboard_interactive(username, prompter)
-                .map_err(Into::into)
-        })
-        .await
+        let res = self
+            .wait_io(|session| {
+                session
+                    .userauth_keyboard_interactive(username, prompter)
+                    .map_err(Into::into)
+            })
+            .await;
+
+        auth::emit(
+            &self.auth_event,
+            AuthMethod::KeyboardInteractive,
+            username,
+            None,
+            &res,
+        );
+        res
+    }
+
+    /// Like `userauth_keyboard_interactive`, but with a prompter that can
+    /// await, e.g. to fetch a one-time password or ask through a GUI.
+    ///
+    /// libssh2 asks for the responses from inside the authentication call, so
+    /// that call runs on a blocking thread while `prompter` is driven here.
+    /// Operations on the session that run into that call while it waits for
+    /// the prompter wait until the responses are in, without blocking their
+    /// thread, which the prompter may need to run.
+    pub async fn userauth_keyboard_interactive_async<P: AsyncKeyboardInteractivePrompt>(
+        &self,
+        username: &str,
+        prompter: &mut P,
+    ) -> io::Result<()> {
+        let (requests, mut pending) = mpsc::unbounded_channel();
+        let session = self.session.clone();
+        let io = self.io.clone();
+        let user = username.to_string();
+        let handle = Handle::current();
+        let auth = task::spawn_blocking(move || {
+            let mut bridge = PromptBridge {
+                requests,
+                io: io.clone(),
+            };
+            handle.block_on(wait::wait_io(&session, &io, || {
+                session
+                    .userauth_keyboard_interactive(&user, &mut bridge)
+                    .map_err(Into::into)
+            }))
+        });
+
+        // the bridge goes away with the blocking task, ending the loop
+        let mut mismatch = None;
+        while let Some(request) = pending.recv().await {
+            let responses = prompter
+                .prompt(&request.username, &request.instructions, &request.prompts)
+                .await;
+            if responses.len() == request.prompts.len() {
+                let _ = request.reply.send(responses);
+            } else {
+                mismatch = Some(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "prompter returned {} responses for {} prompts",
+                        responses.len(),
+                        request.prompts.len()
+                    ),
+                ));
+            }
+        }
+
+        let res = match auth.await {
+            Ok(res) => res.map_err(|e| mismatch.unwrap_or(e)),
+            Err(e) => Err(io::Error::other(e)),
+        };
+
+        auth::emit(
+            &self.auth_event,
+            AuthMethod::KeyboardInteractive,
+            username,
+            None,
+            &res,
+        );
+        res
     }
 
     pub async fn userauth_agent(&self, username: &str) -> io::Result<()> {
-        self.wait_io(|session| session.userauth_agent(username).map_err(Into::into))
-            .await
+        let res = self
+            .wait_io(|session| session.userauth_agent(username).map_err(Into::into))
+            .await;
+
+        // libssh2 picks the identity itself, so there is no single key to report
+        auth::emit(&self.auth_event, AuthMethod::Agent, username, None, &res);
+        res
     }
 
     pub async fn userauth_pubkey_file(
@@ -129,14 +337,34 @@ impl AsyncSession {
         privatekey: &Path,
         passphrase: Option<&str>,
     ) -> io::Result<()> {
-        self.wait_io(|session| {
-            session
-                .userauth_pubkey_file(username, pubkey, privatekey, passphrase)
-                .map_err(Into::into)
-        })
-        .await
+        let res = self
+            .wait_io(|session| {
+                session
+                    .userauth_pubkey_file(username, pubkey, privatekey, passphrase)
+                    .map_err(Into::into)
+            })
+            .await;
+
+        if self.auth_event.is_some() {
+            let fingerprint = match pubkey {
+                Some(pubkey) => fs::read_to_string(pubkey)
+                    .await
+                    .ok()
+                    .and_then(|pubkey| auth::fingerprint_openssh(&pubkey)),
+                None => None,
+            };
+            auth::emit(
+                &self.auth_event,
+                AuthMethod::PublicKeyFile,
+                username,
+                fingerprint,
+                &res,
+            );
+        }
+        res
     }
 
+    #[cfg(any(unix, feature = "vendored-openssl", feature = "openssl-on-win32"))]
     pub async fn userauth_pubkey_memory(
         &self,
         username: &str,
@@ -144,12 +372,22 @@ impl AsyncSession {
         privatekeydata: &str,
         passphrase: Option<&str>,
     ) -> io::Result<()> {
-        self.wait_io(|session| {
-            session
-                .userauth_pubkey_memory(username, pubkeydata, privatekeydata, passphrase)
-                .map_err(Into::into)
-        })
-        .await
+        let res = self
+            .wait_io(|session| {
+                session
+                    .userauth_pubkey_memory(username, pubkeydata, privatekeydata, passphrase)
+                    .map_err(Into::into)
+            })
+            .await;
+
+        auth::emit(
+            &self.auth_event,
+            AuthMethod::PublicKeyMemory,
+            username,
+            pubkeydata.and_then(auth::fingerprint_openssh),
+            &res,
+        );
+        res
     }
 
     pub async fn userauth_hostbased_file(
@@ -161,19 +399,41 @@ impl AsyncSession {
         hostname: &str,
         local_username: Option<&str>,
     ) -> io::Result<()> {
-        self.wait_io(|session| {
-            session
-                .userauth_hostbased_file(
-                    username,
-                    publickey,
-                    privatekey,
-                    passphrase,
-                    hostname,
-                    local_username,
-                )
-                .map_err(Into::into)
-        })
-        .await
+        let res = self
+            .wait_io(|session| {
+                session
+                    .userauth_hostbased_file(
+                        username,
+                        publickey,
+                        privatekey,
+                        passphrase,
+                        hostname,
+                        local_username,
+                    )
+                    .map_err(Into::into)
+            })
+            .await;
+
+        if self.auth_event.is_some() {
+            let fingerprint = fs::read_to_string(publickey)
+                .await
+                .ok()
+                .and_then(|publickey| auth::fingerprint_openssh(&publickey));
+            auth::emit(
+                &self.auth_event,
+                AuthMethod::HostBasedFile,
+                username,
+                fingerprint,
+                &res,
+            );
+        }
+        res
+    }
+
+    /// Call `callback` after every authentication attempt made through this
+    /// session or an agent obtained from it afterwards.
+    pub fn on_auth_event(&mut self, callback: impl Fn(&AuthEvent<'_>) + Send + Sync + 'static) {
+        self.auth_event = Some(Arc::new(callback));
     }
 
     pub fn authenticated(&self) -> bool {
@@ -190,6 +450,49 @@ impl AsyncSession {
             .await
     }
 
+    /// Prefer the SHA-1 Diffie-Hellman groups old appliances are limited to,
+    /// or go back to the modern order with them last. Must be called before
+    /// `handshake`.
+    ///
+    /// libssh2 offers all of them by default since 1.7.0, this only moves them
+    /// to the front so a server that picks the first match gets one it knows.
+    pub async fn compat_old_kex(&self, enable: bool) -> io::Result<()> {
+        let prefs = if enable {
+            LEGACY_KEX_PREFS
+        } else {
+            MODERN_KEX_PREFS
+        };
+        self.method_pref(MethodType::Kex, prefs).await
+    }
+
+    /// Stop offering the strict kex extension (the Terrapin countermeasure).
+    ///
+    /// Always fails with `Unsupported` when `disable` is set: since 1.11.1
+    /// libssh2 prepends `ext-info-c,kex-strict-c-v00@openssh.com` to any kex
+    /// preference list and has no option to leave it out.
+    pub async fn compat_disable_strict_kex(&self, disable: bool) -> io::Result<()> {
+        if disable {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "libssh2 always offers kex-strict-c-v00@openssh.com",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Everything an old appliance is likely to need: `compat_old_kex(true)`
+    /// plus `ssh-rsa`/`ssh-dss` host keys and CBC ciphers preferred. Strict
+    /// kex stays on, see `compat_disable_strict_kex`.
+    pub async fn compat_legacy_device(&self) -> io::Result<()> {
+        self.compat_old_kex(true).await?;
+        self.method_pref(MethodType::HostKey, LEGACY_HOSTKEY_PREFS)
+            .await?;
+        self.method_pref(MethodType::CryptCs, LEGACY_CRYPT_PREFS)
+            .await?;
+        self.method_pref(MethodType::CryptSc, LEGACY_CRYPT_PREFS)
+            .await
+    }
+
     pub fn methods(&self, method_type: MethodType) -> Option<&str> {
         self.session.methods(method_type)
     }
@@ -208,6 +511,7 @@ impl AsyncSession {
             agent,
             session: self.session.clone(),
             io: self.io.clone(),
+            auth_event: self.auth_event.clone(),
         })
     }
 
@@ -215,16 +519,94 @@ impl AsyncSession {
         self.session.known_hosts().map_err(Into::into)
     }
 
+    /// Set the file `verify_host_key` checks against, defaults to
+    /// `~/.ssh/known_hosts`.
+    pub fn set_known_hosts_file(&mut self, path: impl Into<PathBuf>) {
+        self.known_hosts_file = Some(path.into());
+    }
+
+    /// Check the server's host key against the known_hosts file.
+    ///
+    /// Hosts that aren't in the file are handled according to `policy`, new
+    /// entries are appended in hashed form. A key that doesn't match the one
+    /// on record fails with [`HostKeyMismatch`] whatever the policy.
+    pub async fn verify_host_key(
+        &self,
+        hostname: &str,
+        port: u16,
+        mut policy: HostKeyPolicy,
+    ) -> io::Result<()> {
+        let (key, key_type) = self.session.host_key().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "no host key, handshake first")
+        })?;
+        let info = HostKeyInfo {
+            key_type,
+            key: key.to_vec(),
+            fingerprint: auth::fingerprint(key),
+        };
+        let path = self.known_hosts_file.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no known_hosts file configured")
+        })?;
+
+        let contents = match fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut known_hosts = self.known_hosts()?;
+        let decision =
+            match known_hosts::check(&mut known_hosts, &contents, hostname, port, &info.key)? {
+                CheckResult::Match => return Ok(()),
+                CheckResult::Mismatch => {
+                    let mismatch = HostKeyMismatch {
+                        host: known_hosts::host_pattern(hostname, port),
+                        fingerprint: info.fingerprint,
+                    };
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, mismatch));
+                }
+                CheckResult::Failure => {
+                    return Err(io::Error::other(
+                        "failed to check host key against known_hosts",
+                    ))
+                }
+                CheckResult::NotFound => match &mut policy {
+                    HostKeyPolicy::Strict => HostKeyDecision::Reject,
+                    HostKeyPolicy::AcceptNew { write_back: true } => HostKeyDecision::AcceptAndSave,
+                    HostKeyPolicy::AcceptNew { write_back: false } => HostKeyDecision::Accept,
+                    HostKeyPolicy::Callback(callback) => callback(&info),
+                },
+            };
+
+        match decision {
+            HostKeyDecision::Accept => Ok(()),
+            HostKeyDecision::AcceptAndSave => {
+                let mut entry = known_hosts::hashed_entry(hostname, port, &info.key)?;
+                if !contents.is_empty() && !contents.ends_with('\n') {
+                    entry.insert(0, '\n');
+                }
+                append_known_host(&path, &entry).await
+            }
+            HostKeyDecision::Reject => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "host key for {} ({}) is not in known_hosts",
+                    known_hosts::host_pattern(hostname, port),
+                    info.fingerprint
+                ),
+            )),
+        }
+    }
+
     pub async fn channel_session(&self) -> io::Result<AsyncChannel> {
         let channel = self
             .wait_io(|session| session.channel_session().map_err(Into::into))
             .await?;
 
-        Ok(AsyncChannel {
+        Ok(AsyncChannel::new(
+            self.session.clone(),
             channel,
-            session: self.session.clone(),
-            io: self.io.clone(),
-        })
+            self.io.clone(),
+        ))
     }
 
     pub async fn channel_direct_tcpip(
@@ -241,11 +623,77 @@ impl AsyncSession {
             })
             .await?;
 
-        Ok(AsyncChannel {
+        Ok(AsyncChannel::new(
+            self.session.clone(),
             channel,
-            session: self.session.clone(),
-            io: self.io.clone(),
-        })
+            self.io.clone(),
+        ))
+    }
+
+    /// Open a channel to the Unix socket at `socket_path` on the remote host,
+    /// e.g. `/var/run/docker.sock`. Use [`AsyncChannel::bridge`] to connect
+    /// it to a local stream.
+    pub async fn channel_direct_streamlocal(
+        &self,
+        socket_path: &str,
+        src: Option<(&str, u16)>,
+    ) -> io::Result<AsyncChannel> {
+        let channel = self
+            .wait_io(|session| {
+                session
+                    .channel_direct_streamlocal(socket_path, src)
+                    .map_err(Into::into)
+            })
+            .await?;
+
+        Ok(AsyncChannel::new(
+            self.session.clone(),
+            channel,
+            self.io.clone(),
+        ))
+    }
+
+    /// Forward every connection accepted on `listener` to the Unix socket at
+    /// `socket_path` on the remote host, each through its own channel and
+    /// [`AsyncChannel::bridge`] task.
+    ///
+    /// Runs until accepting a connection or opening a channel fails, errors
+    /// of a single bridged connection only end that connection.
+    pub async fn forward_streamlocal(
+        &self,
+        listener: &TcpListener,
+        socket_path: &str,
+    ) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            self.spawn_streamlocal_bridge(socket_path, stream).await?;
+        }
+    }
+
+    /// Like [`forward_streamlocal`](Self::forward_streamlocal), for a local
+    /// Unix socket.
+    #[cfg(unix)]
+    pub async fn forward_streamlocal_unix(
+        &self,
+        listener: &UnixListener,
+        socket_path: &str,
+    ) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            self.spawn_streamlocal_bridge(socket_path, stream).await?;
+        }
+    }
+
+    async fn spawn_streamlocal_bridge<S>(&self, socket_path: &str, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let channel = self.channel_direct_streamlocal(socket_path, None).await?;
+        task::spawn(async move {
+            let _ = channel.bridge(stream).await;
+        });
+
+        Ok(())
     }
 
     pub async fn channel_forward_listen(
@@ -263,26 +711,18 @@ impl AsyncSession {
             .await?;
 
         Ok((
-            AsyncListener {
-                listener,
-                session: self.session.clone(),
-                io: self.io.clone(),
-            },
+            AsyncListener::new(self.session.clone(), listener, self.io.clone()),
             port,
         ))
     }
 
     pub async fn scp_recv(&self, path: &Path) -> io::Result<(AsyncChannel, ScpFileStat)> {
         let (channel, stat) = self
-            .wait_io(|session| session.scp_recv(path).map_err(Into::into))
+            .wait_io(|session| session.scp_recv(path).map_err(error::from_ssh2))
             .await?;
 
         Ok((
-            AsyncChannel {
-                channel,
-                session: self.session.clone(),
-                io: self.io.clone(),
-            },
+            AsyncChannel::new(self.session.clone(), channel, self.io.clone()),
             stat,
         ))
     }
@@ -298,35 +738,114 @@ impl AsyncSession {
             .wait_io(|session| {
                 session
                     .scp_send(remote_path, mode, size, times)
-                    .map_err(Into::into)
+                    .map_err(error::from_ssh2)
             })
             .await?;
 
-        Ok(AsyncChannel {
+        Ok(AsyncChannel::new(
+            self.session.clone(),
             channel,
-            session: self.session.clone(),
-            io: self.io.clone(),
-        })
+            self.io.clone(),
+        ))
+    }
+
+    /// Send the local file `local` to `remote` over SCP, creating it with
+    /// `mode` and the local modification and access times. The channel is
+    /// closed before returning, also on failure.
+    pub async fn scp_send_file(&self, local: &Path, remote: &Path, mode: i32) -> io::Result<()> {
+        let mut file = fs::File::open(local).await?;
+        let meta = file.metadata().await?;
+        let size = meta.len();
+        let times = match (meta.modified(), meta.accessed()) {
+            (Ok(mtime), Ok(atime)) => Some((unix_secs(mtime), unix_secs(atime))),
+            _ => None,
+        };
+        let mut channel = self.scp_send(remote, mode, size, times).await?;
+
+        let res = scp_send_data(&mut channel, &mut file, size).await;
+        if res.is_err() {
+            scp_abort(&mut channel).await;
+        }
+
+        res
+    }
+
+    /// Receive `remote` over SCP into the local file `local`, applying the
+    /// remote mode to it. Exactly `size()` bytes of the returned stat are
+    /// written. The channel is closed before returning, also on failure.
+    ///
+    /// The remote modification time is not applied: `ScpFileStat` only
+    /// exposes size and mode. Use [`AsyncSftp::stat`] on `remote` if the
+    /// mtime is needed.
+    pub async fn scp_recv_file(&self, remote: &Path, local: &Path) -> io::Result<ScpFileStat> {
+        let (mut channel, stat) = self.scp_recv(remote).await?;
+
+        let res = scp_recv_data(&mut channel, local, &stat).await;
+        if res.is_err() {
+            scp_abort(&mut channel).await;
+        }
+
+        res.map(|_| stat)
     }
 
     pub async fn sftp(&self) -> io::Result<AsyncSftp> {
         let sftp = self
-            .wait_io(|session| session.sftp().map_err(Into::into))
+            .wait_io(|session| session.sftp().map_err(error::from_ssh2))
             .await?;
 
         Ok(AsyncSftp {
             sftp,
             session: self.session.clone(),
             io: self.io.clone(),
+            modes: Default::default(),
         })
     }
 
+    /// Open a channel of an arbitrary type.
+    ///
+    /// `packet_size` must not be zero and `window_size` not smaller than it,
+    /// otherwise this fails with `InvalidInput` before anything is sent.
+    /// `packet_size` is then clamped to the 1024..=32768 range libssh2 handles
+    /// well, and `window_size` raised to the clamped packet size if it is
+    /// smaller. Either adjustment is logged as a warning; the values actually
+    /// used are available from [`AsyncChannel::window_size`] and
+    /// [`AsyncChannel::packet_size`].
     pub async fn channel_open(
         &self,
         channel_type: &str,
         window_size: u32,
         packet_size: u32,
         message: Option<&str>,
+    ) -> io::Result<AsyncChannel> {
+        check_channel_params(window_size, packet_size)?;
+        let (window_size, packet_size) = clamp_channel_params(window_size, packet_size);
+
+        self.open_channel(channel_type, window_size, packet_size, message)
+            .await
+    }
+
+    /// Like [`channel_open`](Self::channel_open), but passes `packet_size` on
+    /// as is, for servers that need a value outside the range libssh2 handles
+    /// well.
+    pub async fn channel_open_unchecked(
+        &self,
+        channel_type: &str,
+        window_size: u32,
+        packet_size: u32,
+        message: Option<&str>,
+    ) -> io::Result<AsyncChannel> {
+        check_channel_params(window_size, packet_size)?;
+
+        self.open_channel(channel_type, window_size, packet_size, message)
+            .await
+    }
+
+    async fn open_channel(
+        &self,
+        channel_type: &str,
+        window_size: u32,
+        packet_size: u32,
+        message: Option<&str>,
     ) -> io::Result<AsyncChannel> {
         let channel = self
             .wait_io(|session| {
@@ -336,11 +855,11 @@ impl AsyncSession {
             })
             .await?;
 
-        Ok(AsyncChannel {
-            channel,
-            session: self.session.clone(),
-            io: self.io.clone(),
-        })
+        let mut channel = AsyncChannel::new(self.session.clone(), channel, self.io.clone());
+        channel.window_size = window_size;
+        channel.packet_size = packet_size;
+
+        Ok(channel)
     }
 
     pub fn banner(&self) -> Option<&str> {
@@ -351,10 +870,32 @@ impl AsyncSession {
         self.session.host_key()
     }
 
+    /// Host keys announced by the server with `hostkeys-00@openssh.com`.
+    ///
+    /// libssh2 answers every global request with a failure inside its packet
+    /// handler and never hands them to the application, so the announcement
+    /// can't be observed and this is always empty.
+    pub fn announced_host_keys(&self) -> Vec<(Vec<u8>, HostKeyType)> {
+        Vec::new()
+    }
+
     pub fn host_key_hash(&self, hash: HashType) -> Option<&[u8]> {
         self.session.host_key_hash(hash)
     }
 
+    /// Configure keepalives, sent every `interval` seconds once the session has
+    /// been idle that long. 0 disables them.
+    pub fn set_keepalive(&self, want_reply: bool, interval: u32) {
+        self.session.set_keepalive(want_reply, interval);
+    }
+
+    /// Send keepalives in a background task, as configured with
+    /// [`set_keepalive`](Self::set_keepalive). Must be called from within a
+    /// tokio runtime.
+    pub fn spawn_keepalive(&self) -> KeepaliveHandle {
+        KeepaliveHandle::spawn(self.session.clone(), self.io.clone())
+    }
+
     pub async fn keepalive_send(&self) -> io::Result<u32> {
         self.wait_io(|session| session.keepalive_send().map_err(Into::into))
             .await
@@ -374,3 +915,143 @@ impl AsyncSession {
         .await
     }
 }
+
+async fn append_known_host(path: &Path, entry: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path).await?;
+    file.write_all(entry.as_bytes()).await?;
+    file.flush().await
+}
+
+// Checked on the values as passed, before any clamping.
+fn check_channel_params(window_size: u32, packet_size: u32) -> io::Result<()> {
+    if packet_size == 0 {
+        return Err(invalid_channel_params("packet_size must not be 0"));
+    }
+    if window_size < packet_size {
+        return Err(invalid_channel_params(format!(
+            "window_size {} is smaller than packet_size {}",
+            window_size, packet_size
+        )));
+    }
+
+    Ok(())
+}
+
+// Keeps `window_size >= packet_size` for the clamped packet size as well.
+fn clamp_channel_params(window_size: u32, packet_size: u32) -> (u32, u32) {
+    let clamped = packet_size.clamp(MIN_PACKET_SIZE, MAX_PACKET_SIZE);
+    if clamped != packet_size {
+        log::warn!(
+            "channel_open: packet_size {} clamped to {}",
+            packet_size,
+            clamped
+        );
+    }
+    if window_size < clamped {
+        log::warn!(
+            "channel_open: window_size {} raised to the packet size {}",
+            window_size,
+            clamped
+        );
+    }
+
+    (window_size.max(clamped), clamped)
+}
+
+fn invalid_channel_params(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+async fn scp_send_data(
+    channel: &mut AsyncChannel,
+    file: &mut fs::File,
+    size: u64,
+) -> io::Result<()> {
+    let mut stream = channel.stream(0)?;
+    let n = tokio::io::copy(&mut file.take(size), &mut stream).await?;
+    if n < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("local file shrank to {} bytes during transfer", n),
+        ));
+    }
+    stream.flush().await?;
+
+    scp_finish(channel).await
+}
+
+// `scp_recv` reads don't stop at the end of the file, the status byte that
+// follows it comes through the same stream.
+async fn scp_recv_data(
+    channel: &mut AsyncChannel,
+    local: &Path,
+    stat: &ScpFileStat,
+) -> io::Result<()> {
+    let mut stream = channel.stream(0)?;
+    let mut file = fs::File::create(local).await?;
+    let n = tokio::io::copy(&mut (&mut stream).take(stat.size()), &mut file).await?;
+    if n < stat.size() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("received {} of {} bytes", n, stat.size()),
+        ));
+    }
+    file.flush().await?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = stat.mode() as u32 & 0o7777;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .await?;
+    }
+
+    scp_finish(channel).await
+}
+
+async fn scp_finish(channel: &mut AsyncChannel) -> io::Result<()> {
+    channel.send_eof().await?;
+    channel.wait_eof().await?;
+    channel.close().await?;
+    channel.wait_close().await
+}
+
+// Best effort, the transfer error is what gets reported.
+async fn scp_abort(channel: &mut AsyncChannel) {
+    if channel.close().await.is_ok() {
+        let _ = channel.wait_close().await;
+    }
+}
+
+fn unix_secs(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamped_packet_size_never_exceeds_the_window() {
+        check_channel_params(1000, 500).unwrap();
+        assert_eq!(clamp_channel_params(1000, 500), (1024, 1024));
+
+        assert_eq!(
+            clamp_channel_params(1 << 20, 1 << 20),
+            (1 << 20, MAX_PACKET_SIZE)
+        );
+        assert_eq!(clamp_channel_params(4096, 2048), (4096, 2048));
+    }
+}
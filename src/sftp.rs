@@ -1,72 +1,131 @@
 #![allow(unused_imports, dead_code)]
 
+use std::fmt;
+use std::fs::{FileTimes, Metadata};
+use std::future::poll_fn;
 use std::io;
 use std::io::{Error, Read, Write};
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_core::Stream;
+use libssh2_sys as raw;
+use ssh2::{ErrorCode, File, FileStat, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::task;
+
+use crate::error;
+use crate::transport::Transport;
+use crate::wait;
+
+const DEFAULT_FILE_MODE: i32 = 0o644;
+const DEFAULT_DIR_MODE: i32 = 0o755;
+
+const DEFAULT_BUFFER_SIZE: usize = 32 * 1024;
+
+type ProgressCallback = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+/// Options for [`AsyncSftp::upload`] and [`AsyncSftp::download`].
+#[derive(Clone)]
+pub struct TransferOptions {
+    /// Permissions of the destination file. Uploads fall back to the default
+    /// file mode when the remote file is created.
+    pub mode: Option<i32>,
+    /// Copy the access and modification times of the source onto the
+    /// destination.
+    pub preserve_mtime: bool,
+    pub buffer_size: usize,
+    /// Called with `(transferred, total)` after each chunk is written. The
+    /// total is `None` if the server doesn't report the size of the source.
+    pub progress: Option<ProgressCallback>,
+    /// Remove the partially written destination if copying into it fails.
+    /// A destination the transfer never got to open is left alone.
+    pub delete_on_error: bool,
+}
 
-use ssh2::{BlockDirections, File, FileStat, OpenFlags, OpenType, RenameFlags, Session, Sftp};
-use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
-use tokio::net::TcpStream;
+impl Default for TransferOptions {
+    fn default() -> Self {
+        TransferOptions {
+            mode: None,
+            preserve_mtime: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            progress: None,
+            delete_on_error: false,
+        }
+    }
+}
 
 pub struct AsyncSftp {
     pub(crate) sftp: Sftp,
     pub(crate) session: Session,
-    pub(crate) io: Arc<TcpStream>,
+    pub(crate) io: Arc<Transport>,
+    pub(crate) modes: DefaultModes,
+}
+
+// An explicit mode always wins over the default of the handle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct DefaultModes {
+    file: i32,
+    dir: i32,
+}
+
+impl Default for DefaultModes {
+    fn default() -> Self {
+        DefaultModes {
+            file: DEFAULT_FILE_MODE,
+            dir: DEFAULT_DIR_MODE,
+        }
+    }
+}
+
+impl DefaultModes {
+    fn file(&self, mode: Option<i32>) -> i32 {
+        mode.unwrap_or(self.file)
+    }
+
+    fn dir(&self, mode: Option<i32>) -> i32 {
+        mode.unwrap_or(self.dir)
+    }
 }
 
 impl AsyncSftp {
     async fn wait_io<R>(&self, mut op: impl FnMut(&Sftp) -> io::Result<R>) -> io::Result<R> {
-        loop {
-            match op(&self.sftp) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let sftp = &self.sftp;
+        wait::wait_io(&self.session, &self.io, || op(sftp)).await
     }
 
     async fn wait_io_mut<R>(
         &mut self,
         mut op: impl FnMut(&mut Sftp) -> io::Result<R>,
     ) -> io::Result<R> {
-        loop {
-            match op(&mut self.sftp) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let sftp = &mut self.sftp;
+        wait::wait_io(&self.session, &self.io, || op(sftp)).await
+    }
+
+    /// Mode for files created without an explicit mode, by `open`, `create`
+    /// and `upload`. Defaults to `0o644`.
+    pub fn set_default_file_mode(&mut self, mode: i32) {
+        self.modes.file = mode;
+    }
+
+    pub fn default_file_mode(&self) -> i32 {
+        self.modes.file
+    }
+
+    /// Mode for directories created by `create_dir` and by `create_dir_all` without an
+    /// explicit mode. Defaults to `0o755`.
+    pub fn set_default_dir_mode(&mut self, mode: i32) {
+        self.modes.dir = mode;
+    }
+
+    pub fn default_dir_mode(&self) -> i32 {
+        self.modes.dir
     }
 
     pub async fn open_mode(
@@ -79,7 +138,7 @@ impl AsyncSftp {
         let file = self
             .wait_io(|sftp| {
                 sftp.open_mode(filename, flags, mode, open_type)
-                    .map_err(Into::into)
+                    .map_err(error::from_ssh2)
             })
             .await?;
 
@@ -87,19 +146,43 @@ impl AsyncSftp {
             file,
             session: self.session.clone(),
             io: self.io.clone(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_ahead: 0,
+            write_buf: Vec::new(),
+            write_buffer_size: 0,
         })
     }
 
     pub async fn open(&self, filename: &Path) -> io::Result<AsyncFile> {
-        self.open_mode(filename, OpenFlags::READ, 0o644, OpenType::File)
-            .await
+        self.open_mode(
+            filename,
+            OpenFlags::READ,
+            self.modes.file(None),
+            OpenType::File,
+        )
+        .await
+    }
+
+    pub async fn read_with_limit(
+        &self,
+        path: impl AsRef<Path>,
+        max_bytes: u64,
+    ) -> io::Result<Vec<u8>> {
+        let mut file = self.open(path.as_ref()).await?;
+        let res = read_limited(&mut file, max_bytes).await;
+        let closed = file.close().await;
+
+        let buf = res?;
+        closed?;
+        Ok(buf)
     }
 
     pub async fn create(&self, filename: &Path) -> io::Result<AsyncFile> {
         self.open_mode(
             filename,
             OpenFlags::WRITE | OpenFlags::TRUNCATE,
-            0o644,
+            self.modes.file(None),
             OpenType::File,
         )
         .await
@@ -110,9 +193,24 @@ impl AsyncSftp {
             .await
     }
 
+    /// Stream the entries of `dirname` one at a time, without `.` and `..`.
+    ///
+    /// Yields the same paths as [`readdir`](Self::readdir), joined onto
+    /// `dirname`. Finish with [`ReadDir::close`], also when stopping early.
+    pub async fn read_dir(&self, dirname: impl AsRef<Path>) -> io::Result<ReadDir> {
+        let dirname = dirname.as_ref();
+        let dir = self.opendir(dirname).await?;
+
+        Ok(ReadDir {
+            dir,
+            dirname: dirname.to_path_buf(),
+            done: false,
+        })
+    }
+
     pub async fn readdir(&self, dirname: &Path) -> io::Result<Vec<(PathBuf, FileStat)>> {
         let entries = self
-            .wait_io(|sftp| sftp.readdir(dirname).map_err(Into::into))
+            .wait_io(|sftp| sftp.readdir(dirname).map_err(error::from_ssh2))
             .await?;
 
         Ok(entries)
@@ -120,40 +218,138 @@ impl AsyncSftp {
 
     pub async fn mkdir(&self, filename: impl AsRef<Path>, mode: i32) -> io::Result<()> {
         let filename = filename.as_ref();
-        self.wait_io(|sftp| sftp.mkdir(filename, mode).map_err(Into::into))
+        self.wait_io(|sftp| sftp.mkdir(filename, mode).map_err(error::from_ssh2))
             .await?;
 
         Ok(())
     }
 
+    /// Like [`mkdir`](Self::mkdir), with the default directory mode.
+    pub async fn create_dir(&self, filename: impl AsRef<Path>) -> io::Result<()> {
+        self.mkdir(filename, self.modes.dir(None)).await
+    }
+
     pub async fn rmdir(&self, filename: impl AsRef<Path>) -> io::Result<()> {
         let filename = filename.as_ref();
-        self.wait_io(|sftp| sftp.rmdir(filename).map_err(Into::into))
+        self.wait_io(|sftp| sftp.rmdir(filename).map_err(error::from_ssh2))
             .await?;
 
         Ok(())
     }
 
-    pub async fn stat(&self, filename: impl AsRef<Path>) -> io::Result<()> {
+    /// Create `path` and all of its missing parents with `mode`, or the
+    /// default directory mode if `None`.
+    ///
+    /// Components that already exist as directories, or symlinks to them, are
+    /// left alone. Fails with `NotADirectory` if one of them is anything else.
+    pub async fn create_dir_all(
+        &self,
+        path: impl AsRef<Path>,
+        mode: Option<i32>,
+    ) -> io::Result<()> {
+        let mut dir = PathBuf::new();
+        for component in path.as_ref().components() {
+            dir.push(component);
+            if matches!(component, Component::RootDir | Component::Prefix(_)) {
+                continue;
+            }
+
+            match self.stat(&dir).await {
+                Ok(stat) if stat.is_dir() => continue,
+                Ok(_) => return Err(not_a_dir(&dir)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(with_path(&dir, e)),
+            }
+
+            if let Err(e) = self.mkdir(&dir, self.modes.dir(mode)).await {
+                // someone else may have created it in the meantime
+                match self.stat(&dir).await {
+                    Ok(stat) if stat.is_dir() => {}
+                    Ok(_) => return Err(not_a_dir(&dir)),
+                    Err(_) => return Err(with_path(&dir, e)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove `path` and everything below it.
+    ///
+    /// Symlinks are unlinked, never followed. On failure the error message
+    /// names the path that couldn't be removed.
+    pub async fn remove_dir_all(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let stat = self.lstat(path).await.map_err(|e| with_path(path, e))?;
+        if !stat.file_type().is_dir() {
+            return self.unlink(path).await.map_err(|e| with_path(path, e));
+        }
+
+        // (dir, listed): a directory is removed once everything in it is gone
+        let mut dirs = vec![(path.to_path_buf(), false)];
+        while let Some((dir, listed)) = dirs.pop() {
+            if listed {
+                self.rmdir(&dir).await.map_err(|e| with_path(&dir, e))?;
+                continue;
+            }
+            dirs.push((dir.clone(), true));
+
+            let mut entries = self.read_dir(&dir).await.map_err(|e| with_path(&dir, e))?;
+            let res = self.remove_entries(&dir, &mut entries, &mut dirs).await;
+            let closed = entries.close().await.map_err(|e| with_path(&dir, e));
+            res?;
+            closed?;
+        }
+
+        Ok(())
+    }
+
+    // Unlink the files in `entries` and queue the directories.
+    async fn remove_entries(
+        &self,
+        dir: &Path,
+        entries: &mut ReadDir,
+        dirs: &mut Vec<(PathBuf, bool)>,
+    ) -> io::Result<()> {
+        while let Some(entry) = poll_fn(|cx| Pin::new(&mut *entries).poll_next(cx)).await {
+            let (entry, stat) = entry.map_err(|e| with_path(dir, e))?;
+            if stat.file_type().is_dir() {
+                dirs.push((entry, false));
+            } else {
+                self.unlink(&entry)
+                    .await
+                    .map_err(|e| with_path(&entry, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn stat(&self, filename: impl AsRef<Path>) -> io::Result<FileStat> {
         let filename = filename.as_ref();
-        self.wait_io(|sftp| sftp.stat(filename).map_err(Into::into))
+        let stat = self
+            .wait_io(|sftp| sftp.stat(filename).map_err(error::from_ssh2))
             .await?;
 
-        Ok(())
+        Ok(stat)
     }
 
-    pub async fn lstat(&self, filename: impl AsRef<Path>) -> io::Result<()> {
+    pub async fn lstat(&self, filename: impl AsRef<Path>) -> io::Result<FileStat> {
         let filename = filename.as_ref();
-        self.wait_io(|sftp| sftp.lstat(filename).map_err(Into::into))
+        let stat = self
+            .wait_io(|sftp| sftp.lstat(filename).map_err(error::from_ssh2))
             .await?;
 
-        Ok(())
+        Ok(stat)
     }
 
     pub async fn setstat(&self, filename: impl AsRef<Path>, stat: FileStat) -> io::Result<()> {
         let filename = filename.as_ref();
-        self.wait_io(|sftp| sftp.setstat(filename, stat.clone()).map_err(Into::into))
-            .await?;
+        self.wait_io(|sftp| {
+            sftp.setstat(filename, stat.clone())
+                .map_err(error::from_ssh2)
+        })
+        .await?;
 
         Ok(())
     }
@@ -166,26 +362,28 @@ impl AsyncSftp {
         let path = path.as_ref();
         let target = target.as_ref();
 
-        self.wait_io(|sftp| sftp.symlink(path, target).map_err(Into::into))
+        self.wait_io(|sftp| sftp.symlink(path, target).map_err(error::from_ssh2))
             .await?;
 
         Ok(())
     }
 
-    pub async fn readlink(&self, path: impl AsRef<Path>) -> io::Result<()> {
+    pub async fn readlink(&self, path: impl AsRef<Path>) -> io::Result<PathBuf> {
         let path = path.as_ref();
-        self.wait_io(|sftp| sftp.readlink(path).map_err(Into::into))
+        let target = self
+            .wait_io(|sftp| sftp.readlink(path).map_err(error::from_ssh2))
             .await?;
 
-        Ok(())
+        Ok(target)
     }
 
-    pub async fn realpath(&self, path: impl AsRef<Path>) -> io::Result<()> {
+    pub async fn realpath(&self, path: impl AsRef<Path>) -> io::Result<PathBuf> {
         let path = path.as_ref();
-        self.wait_io(|sftp| sftp.realpath(path).map_err(Into::into))
+        let target = self
+            .wait_io(|sftp| sftp.realpath(path).map_err(error::from_ssh2))
             .await?;
 
-        Ok(())
+        Ok(target)
     }
 
     pub async fn rename(
@@ -196,7 +394,7 @@ impl AsyncSftp {
     ) -> io::Result<()> {
         let src = src.as_ref();
         let dst = dst.as_ref();
-        self.wait_io(|sftp| sftp.rename(src, dst, flags).map_err(Into::into))
+        self.wait_io(|sftp| sftp.rename(src, dst, flags).map_err(error::from_ssh2))
             .await?;
 
         Ok(())
@@ -204,17 +402,69 @@ impl AsyncSftp {
 
     pub async fn unlink(&self, file: impl AsRef<Path>) -> io::Result<()> {
         let file = file.as_ref();
-        self.wait_io(|sftp| sftp.unlink(file).map_err(Into::into))
+        self.wait_io(|sftp| sftp.unlink(file).map_err(error::from_ssh2))
             .await?;
 
         Ok(())
     }
 
+    /// Copy the local file `local` to `remote`, returning the number of bytes
+    /// written. The remote file is fsync'ed before returning if the server
+    /// supports it.
+    ///
+    /// Errors once `remote` is open carry a [`TransferError`] with the number
+    /// of bytes the server took before the failure.
+    pub async fn upload(
+        &self,
+        local: impl AsRef<Path>,
+        remote: impl AsRef<Path>,
+        opts: TransferOptions,
+    ) -> io::Result<u64> {
+        let remote = remote.as_ref();
+        let mut src = fs::File::open(local).await?;
+        let meta = src.metadata().await?;
+
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let mode = self.modes.file(opts.mode);
+        let mut dst = self.open_mode(remote, flags, mode, OpenType::File).await?;
+
+        let mut transferred = 0;
+        let res = upload_to(&mut src, &mut dst, &meta, &opts, &mut transferred).await;
+        let closed = dst.close().await;
+        let res = res.and_then(|n| closed.map(|_| n));
+        if res.is_err() && opts.delete_on_error {
+            let _ = self.unlink(remote).await;
+        }
+
+        res.map_err(|e| transfer_error(transferred, e))
+    }
+
+    /// Copy `remote` to the local file `local`, returning the number of bytes
+    /// read.
+    ///
+    /// Errors once `remote` is open carry a [`TransferError`] with the number
+    /// of bytes written to `local` before the failure.
+    pub async fn download(
+        &self,
+        remote: impl AsRef<Path>,
+        local: impl AsRef<Path>,
+        opts: TransferOptions,
+    ) -> io::Result<u64> {
+        let local = local.as_ref();
+        let mut src = self.open(remote.as_ref()).await?;
+        let mut transferred = 0;
+        let res = download_from(&mut src, local, &opts, &mut transferred).await;
+        let closed = src.close().await;
+        let res = res.and_then(|n| closed.map(|_| n));
+
+        res.map_err(|e| transfer_error(transferred, e))
+    }
+
     pub async fn shutdown(&mut self) -> io::Result<()> {
         self.wait_io_mut(|sftp| {
             // call hidden shutdown
             // see document for ssh2::Sftp::shutdown
-            sftp.shutdown().map_err(Into::into)
+            sftp.shutdown().map_err(error::from_ssh2)
         })
         .await?;
 
@@ -222,162 +472,535 @@ impl AsyncSftp {
     }
 }
 
+/// A remote file.
+///
+/// Reads and writes fail with the kind of the server's SFTP status, like any
+/// other method, so a full disk or exceeded quota is `StorageFull`. OpenSSH
+/// speaks SFTP version 3, which has no status for either, and reports them as
+/// a plain failure, `Other`.
 pub struct AsyncFile {
     file: File,
     session: Session,
-    io: Arc<TcpStream>,
+    io: Arc<Transport>,
+    // `read_buf[read_pos..]` is read ahead but not yet returned
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_ahead: usize,
+    write_buf: Vec<u8>,
+    write_buffer_size: usize,
 }
 
 impl AsyncFile {
     async fn wait_io<R>(&self, mut op: impl FnMut(&File) -> io::Result<R>) -> io::Result<R> {
-        loop {
-            match op(&self.file) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        let file = &self.file;
+        wait::wait_io(&self.session, &self.io, || op(file)).await
     }
 
     async fn wait_io_mut<R>(
         &mut self,
         mut op: impl FnMut(&mut File) -> io::Result<R>,
     ) -> io::Result<R> {
-        loop {
-            match op(&mut self.file) {
-                Ok(r) => {
-                    return Ok(r);
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let dir = self.session.block_directions();
-                    let interest = match dir {
-                        BlockDirections::None => {
-                            panic!()
-                        }
-                        BlockDirections::Inbound => Interest::READABLE,
-                        BlockDirections::Outbound => Interest::WRITABLE,
-                        BlockDirections::Both => Interest::READABLE.add(Interest::WRITABLE),
-                    };
-                    self.io.ready(interest).await?;
-                }
-                Err(e) => return Err(e),
-            }
+        let file = &mut self.file;
+        wait::wait_io(&self.session, &self.io, || op(file)).await
+    }
+
+    /// Read through an internal buffer of `size` bytes, `0` turns it off.
+    ///
+    /// libssh2 keeps up to four times the size of a read in flight, so
+    /// reading a large buffer pipelines many requests instead of waiting a
+    /// round trip for every small read. Sizes past 2 MiB don't add to that.
+    pub fn set_read_ahead(&mut self, size: usize) {
+        self.read_ahead = size;
+        if self.read_pos == self.read_buf.len() {
+            self.read_buf = Vec::new();
+            self.read_pos = 0;
         }
     }
 
+    /// Collect small writes and send them in batches of up to `size` bytes,
+    /// `0` turns it off.
+    ///
+    /// Batches go out when the buffer is full, on flush and on `fsync`.
+    /// Dropping the file without flushing loses whatever is still buffered.
+    pub fn set_write_buffer(&mut self, size: usize) {
+        self.write_buffer_size = size;
+    }
+
+    /// Flushes the write buffer first, so the stat applies after those writes.
     pub async fn setstat(&mut self, stat: FileStat) -> io::Result<()> {
-        self.wait_io_mut(|f| f.setstat(stat.clone()).map_err(Into::into))
+        poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        self.wait_io_mut(|f| f.setstat(stat.clone()).map_err(error::from_ssh2))
             .await?;
 
         Ok(())
     }
 
+    /// Flushes the write buffer first, so the size includes buffered writes.
     pub async fn stat(&mut self) -> io::Result<FileStat> {
-        let stat = self.wait_io_mut(|f| f.stat().map_err(Into::into)).await?;
+        poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        let stat = self
+            .wait_io_mut(|f| f.stat().map_err(error::from_ssh2))
+            .await?;
 
         Ok(stat)
     }
 
     pub async fn readdir(&mut self) -> io::Result<(PathBuf, FileStat)> {
         let res = self
-            .wait_io_mut(|f| f.readdir().map_err(Into::into))
+            .wait_io_mut(|f| f.readdir().map_err(error::from_ssh2))
             .await?;
 
         Ok(res)
     }
 
+    /// Flush the write buffer and close the handle.
+    ///
+    /// Dropping the file closes it too, but ssh2 does that by switching the
+    /// whole session to blocking mode for the round trip, stalling the runtime
+    /// thread and every other task on the session.
+    pub async fn close(mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        self.wait_io_mut(|f| f.close().map_err(error::from_ssh2))
+            .await
+    }
+
     pub async fn fsync(&mut self) -> io::Result<()> {
-        self.wait_io_mut(|f| f.fsync().map_err(Into::into)).await?;
+        poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        self.wait_io_mut(|f| f.fsync().map_err(error::from_ssh2))
+            .await?;
 
         Ok(())
     }
 }
 
+pub struct ReadDir {
+    dir: AsyncFile,
+    dirname: PathBuf,
+    done: bool,
+}
+
+impl ReadDir {
+    /// Close the directory handle, see [`AsyncFile::close`].
+    pub async fn close(self) -> io::Result<()> {
+        self.dir.close().await
+    }
+}
+
+impl Stream for ReadDir {
+    type Item = io::Result<(PathBuf, FileStat)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let dir = &mut this.dir;
+        let file = &mut dir.file;
+
+        while !this.done {
+            let entry = ready!(wait::poll_io(&dir.session, &dir.io, cx, || {
+                match file.readdir() {
+                    Ok(entry) => Ok(Some(entry)),
+                    // libssh2's way of saying there are no more entries
+                    Err(e) if e.code() == ErrorCode::Session(raw::LIBSSH2_ERROR_FILE) => Ok(None),
+                    Err(e) => Err(error::from_ssh2(e)),
+                }
+            }));
+
+            match entry {
+                Ok(Some((filename, _)))
+                    if filename == Path::new(".") || filename == Path::new("..") => {}
+                Ok(Some((filename, stat))) => {
+                    return Poll::Ready(Some(Ok((this.dirname.join(filename), stat))));
+                }
+                Ok(None) => this.done = true,
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+impl AsyncFile {
+    fn poll_read_direct(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let file = &mut self.file;
+
+        let b = unsafe { &mut *(buf.unfilled_mut() as *mut [MaybeUninit<u8>] as *mut [u8]) };
+        let r = ready!(wait::poll_io(&self.session, &self.io, cx, || {
+            file.read(b).map_err(error::from_io)
+        }))?;
+        unsafe {
+            buf.assume_init(r);
+        }
+        buf.advance(r);
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let file = &mut self.file;
+        let read_buf = &mut self.read_buf;
+
+        read_buf.resize(self.read_ahead, 0);
+        let res = wait::poll_io(&self.session, &self.io, cx, || {
+            file.read(read_buf).map_err(error::from_io)
+        });
+        // nothing buffered unless the read went through
+        let n = match res {
+            Poll::Ready(Ok(n)) => n,
+            _ => 0,
+        };
+        self.read_buf.truncate(n);
+        self.read_pos = 0;
+
+        res.map_ok(|_| ())
+    }
+
+    // libssh2 sends the whole buffer off at once and returns how much of it
+    // was acknowledged, the rest has to be passed again on the next call.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let file = &mut self.file;
+
+        while !self.write_buf.is_empty() {
+            let write_buf = &self.write_buf;
+            let n = ready!(wait::poll_io(&self.session, &self.io, cx, || {
+                file.write(write_buf).map_err(error::from_io)
+            }))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
 impl AsyncRead for AsyncFile {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        let io = self.io.clone();
-        let file = &mut self.file;
+        let this = &mut *self;
 
-        loop {
-            match io.poll_read_ready(cx)? {
-                Poll::Ready(_) => {
-                    let b = unsafe {
-                        &mut *(buf.unfilled_mut() as *mut [MaybeUninit<u8>] as *mut [u8])
-                    };
-
-                    match file.read(b) {
-                        Ok(r) => {
-                            unsafe {
-                                buf.assume_init(r);
-                            }
-                            buf.advance(r);
-                            return Poll::Ready(Ok(()));
-                        }
-                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                        Err(e) => return Poll::Ready(Err(e)),
-                    }
-                }
-                Poll::Pending => return Poll::Pending,
+        if this.read_pos == this.read_buf.len() {
+            if this.read_ahead == 0 || buf.remaining() >= this.read_ahead {
+                return this.poll_read_direct(cx, buf);
             }
+            ready!(this.poll_fill_buf(cx))?;
         }
+
+        let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+
+        Poll::Ready(Ok(()))
     }
 }
 
+// `transferred` counts what `dst` took, so it is still right when this fails.
+async fn copy_chunks<R, W>(
+    src: &mut R,
+    dst: &mut W,
+    total: Option<u64>,
+    opts: &TransferOptions,
+    transferred: &mut u64,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; opts.buffer_size.max(1)];
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        dst.write_all(&buf[..n]).await?;
+        *transferred += n as u64;
+        if let Some(progress) = &opts.progress {
+            progress(*transferred, total);
+        }
+    }
+    dst.flush().await?;
+
+    Ok(*transferred)
+}
+
+async fn upload_to(
+    src: &mut fs::File,
+    dst: &mut AsyncFile,
+    meta: &Metadata,
+    opts: &TransferOptions,
+    transferred: &mut u64,
+) -> io::Result<u64> {
+    let n = copy_chunks(src, dst, Some(meta.len()), opts, transferred).await?;
+
+    match dst.fsync().await {
+        Err(e) if e.kind() != io::ErrorKind::Unsupported => return Err(e),
+        _ => {}
+    }
+
+    // SFTP sets both times or neither, fall back to the modification time
+    // where the access time isn't available
+    let (atime, mtime) = if opts.preserve_mtime {
+        let mtime = unix_time(meta.modified()?);
+        let atime = meta.accessed().map_or(mtime, unix_time);
+        (Some(atime), Some(mtime))
+    } else {
+        (None, None)
+    };
+    if opts.mode.is_some() || mtime.is_some() {
+        dst.setstat(FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: opts.mode.map(|mode| mode as u32),
+            atime,
+            mtime,
+        })
+        .await?;
+    }
+
+    Ok(n)
+}
+
+// Only a file this call created, and didn't manage to fill, is removed.
+async fn download_from(
+    src: &mut AsyncFile,
+    local: &Path,
+    opts: &TransferOptions,
+    transferred: &mut u64,
+) -> io::Result<u64> {
+    let stat = src.stat().await?;
+    let mut dst = fs::File::create(local).await?;
+
+    let res = download_to(src, &mut dst, &stat, opts, transferred).await;
+    if res.is_err() && opts.delete_on_error {
+        drop(dst);
+        let _ = fs::remove_file(local).await;
+    }
+
+    res
+}
+
+async fn download_to(
+    src: &mut AsyncFile,
+    dst: &mut fs::File,
+    stat: &FileStat,
+    opts: &TransferOptions,
+    transferred: &mut u64,
+) -> io::Result<u64> {
+    let n = copy_chunks(src, dst, stat.size, opts, transferred).await?;
+
+    #[cfg(unix)]
+    if let Some(mode) = opts.mode {
+        use std::os::unix::fs::PermissionsExt;
+
+        dst.set_permissions(std::fs::Permissions::from_mode(mode as u32))
+            .await?;
+    }
+
+    if let (true, Some(mtime)) = (opts.preserve_mtime, stat.mtime) {
+        let mut times = FileTimes::new().set_modified(UNIX_EPOCH + Duration::from_secs(mtime));
+        if let Some(atime) = stat.atime {
+            times = times.set_accessed(UNIX_EPOCH + Duration::from_secs(atime));
+        }
+        let file = dst.try_clone().await?.into_std().await;
+        task::spawn_blocking(move || file.set_times(times))
+            .await
+            .map_err(io::Error::other)??;
+    }
+
+    Ok(n)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        PathError {
+            path: path.to_path_buf(),
+            source: e,
+        },
+    )
+}
+
+// Names the path in the message, while keeping the original error reachable
+// through `source` for callers looking for a `ConnectionLost` or ssh2 code.
+#[derive(Debug)]
+struct PathError {
+    path: PathBuf,
+    source: io::Error,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.source)
+    }
+}
+
+impl std::error::Error for PathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Returned, wrapped in an `io::Error` of the same kind as its source, by
+/// [`AsyncSftp::upload`] and [`AsyncSftp::download`] once the destination is
+/// open.
+///
+/// `bytes_written_before_error` is how much of the source the destination
+/// took before the transfer failed, where a resumed transfer can pick up.
+#[derive(Debug)]
+pub struct TransferError {
+    pub bytes_written_before_error: u64,
+    source: io::Error,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transfer failed after {} bytes: {}",
+            self.bytes_written_before_error, self.source
+        )
+    }
+}
+
+impl std::error::Error for TransferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+fn transfer_error(transferred: u64, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        TransferError {
+            bytes_written_before_error: transferred,
+            source: e,
+        },
+    )
+}
+
+fn not_a_dir(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotADirectory,
+        format!("{}: exists and is not a directory", path.display()),
+    )
+}
+
+async fn read_limited(file: &mut AsyncFile, max_bytes: u64) -> io::Result<Vec<u8>> {
+    let size = file.stat().await?.size;
+    if let Some(size) = size {
+        if size > max_bytes {
+            return Err(too_large(Some(size), max_bytes));
+        }
+    }
+
+    // the size may be missing or the file may grow while reading, read one
+    // byte past the limit to notice
+    let capacity = size.unwrap_or(0).min(max_bytes).min(usize::MAX as u64);
+    let mut buf = Vec::with_capacity(capacity as usize);
+    file.take(max_bytes.saturating_add(1))
+        .read_to_end(&mut buf)
+        .await?;
+
+    if buf.len() as u64 > max_bytes {
+        return Err(too_large(None, max_bytes));
+    }
+
+    Ok(buf)
+}
+
+fn too_large(size: Option<u64>, limit: u64) -> io::Error {
+    let msg = match size {
+        Some(size) => format!("file is {} bytes, exceeding the limit of {}", size, limit),
+        None => format!("file exceeds the limit of {} bytes", limit),
+    };
+
+    io::Error::new(io::ErrorKind::FileTooLarge, msg)
+}
+
 impl AsyncWrite for AsyncFile {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
-        let io = self.io.clone();
-        let file = &mut self.file;
+        let this = &mut *self;
 
-        loop {
-            match io.poll_write_ready(cx)? {
-                Poll::Ready(_) => match file.write(buf) {
-                    Ok(r) => return Poll::Ready(Ok(r)),
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                    Err(e) => return Poll::Ready(Err(e)),
-                },
-                Poll::Pending => return Poll::Pending,
-            }
+        if this.write_buf.len() + buf.len() > this.write_buffer_size {
+            ready!(this.poll_write_buf(cx))?;
         }
+        if buf.len() < this.write_buffer_size {
+            this.write_buf.extend_from_slice(buf);
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        let file = &mut this.file;
+        wait::poll_io(&this.session, &this.io, cx, || {
+            file.write(buf).map_err(error::from_io)
+        })
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        let io = self.io.clone();
-        let file = &mut self.file;
+        let this = &mut *self;
+        ready!(this.poll_write_buf(cx))?;
 
-        loop {
-            match io.poll_write_ready(cx)? {
-                Poll::Ready(_) => match file.flush() {
-                    Ok(_) => return Poll::Ready(Ok(())),
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                    Err(e) => return Poll::Ready(Err(e)),
-                },
-                Poll::Pending => return Poll::Pending,
-            }
-        }
+        let file = &mut this.file;
+        wait::poll_io(&this.session, &this.io, cx, || {
+            file.flush().map_err(error::from_io)
+        })
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
         self.poll_flush(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_mode_wins_over_default() {
+        let mut modes = DefaultModes::default();
+        assert_eq!(modes.file(None), 0o644);
+        assert_eq!(modes.dir(None), 0o755);
+
+        modes.file = 0o600;
+        modes.dir = 0o700;
+        assert_eq!(modes.file(None), 0o600);
+        assert_eq!(modes.dir(None), 0o700);
+        assert_eq!(modes.file(Some(0o640)), 0o640);
+        assert_eq!(modes.dir(Some(0o750)), 0o750);
+    }
+
+    #[test]
+    fn with_path_names_the_path_and_keeps_the_source() {
+        let e = with_path(
+            Path::new("/a/b"),
+            io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+        );
+
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(e.to_string(), "/a/b: denied");
+        let source = e.get_ref().unwrap().source().unwrap();
+        let source = source.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
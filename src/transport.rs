@@ -0,0 +1,416 @@
+use std::error::Error;
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::mem::MaybeUninit;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+use libssh2_sys as raw;
+use socket2::SockRef;
+use ssh2::{ErrorCode, Session};
+use tokio::net::TcpStream;
+
+/// Returned by every operation on a session once its connection has failed.
+///
+/// The error that first broke the connection is kept as the source, so all
+/// channels, sftp handles and the session itself report the same cause.
+#[derive(Debug, Clone)]
+pub struct ConnectionLost {
+    source: Arc<io::Error>,
+}
+
+impl ConnectionLost {
+    fn to_io_error(&self) -> io::Error {
+        let kind = match self.source.kind() {
+            io::ErrorKind::Other => io::ErrorKind::ConnectionAborted,
+            kind => kind,
+        };
+
+        io::Error::new(kind, self.clone())
+    }
+}
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection lost: {}", self.source)
+    }
+}
+
+impl Error for ConnectionLost {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+// Shared by a session and every object derived from it. A reset socket wakes
+// all tasks waiting on it, each of them then runs into `check` and fails with
+// the recorded error instead of touching libssh2 again.
+pub(crate) struct Transport {
+    stream: TcpStream,
+    op: Mutex<OpState>,
+    released: Condvar,
+    prompt_wakers: Arc<WakerSet>,
+    lost: Mutex<Option<ConnectionLost>>,
+    op_timeout: Mutex<Option<Duration>>,
+    read_wakers: Arc<WakerSet>,
+    write_wakers: Arc<WakerSet>,
+}
+
+impl Transport {
+    pub(crate) fn new(stream: TcpStream) -> Self {
+        Transport {
+            stream,
+            op: Mutex::new(OpState::default()),
+            released: Condvar::new(),
+            prompt_wakers: Arc::new(WakerSet::default()),
+            lost: Mutex::new(None),
+            op_timeout: Mutex::new(None),
+            read_wakers: Arc::new(WakerSet::default()),
+            write_wakers: Arc::new(WakerSet::default()),
+        }
+    }
+
+    // Held around a libssh2 call and everything that reads the session state it
+    // leaves behind, like `block_directions` or the last error.
+    //
+    // A keyboard-interactive prompt keeps it held until a task answers, and
+    // that task may need the very thread that would wait here. So rather than
+    // block behind a prompt, fail with `Prompting` and let the caller park on
+    // `poll_prompt_done`. Other holders are out of a non-blocking libssh2 call
+    // quickly, those are simply waited for.
+    pub(crate) fn lock(&self) -> Result<OpGuard<'_>, Prompting> {
+        let mut state = self.op_state();
+        loop {
+            if state.prompting {
+                return Err(Prompting);
+            }
+            if !state.held {
+                state.held = true;
+                return Ok(OpGuard { io: self });
+            }
+            state = self
+                .released
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn op_state(&self) -> MutexGuard<'_, OpState> {
+        self.op.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Set by the thread holding the lock while it waits for prompt responses.
+    pub(crate) fn set_prompting(&self, prompting: bool) {
+        self.op_state().prompting = prompting;
+        if prompting {
+            // those blocked in `lock` give up and park instead
+            self.released.notify_all();
+        } else {
+            self.prompt_wakers.wake_by_ref();
+        }
+    }
+
+    // Ready once no prompt holds the lock. Registered before checking, so the
+    // end of the prompt can't slip in between.
+    pub(crate) fn poll_prompt_done(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.prompt_wakers.register(cx.waker());
+        if self.op_state().prompting {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    pub(crate) async fn lock_after_prompt(&self) -> OpGuard<'_> {
+        loop {
+            match self.lock() {
+                Ok(guard) => return guard,
+                Err(Prompting) => poll_fn(|cx| self.poll_prompt_done(cx)).await,
+            }
+        }
+    }
+
+    pub(crate) fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    // `TcpStream::poll_read_ready` only keeps the waker of the most recent
+    // caller, which strands every other task polling a stream of this session.
+    // Register all of them and hand tokio a waker that wakes them together.
+    pub(crate) fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let waker = self.read_wakers.register(cx.waker());
+        self.stream
+            .poll_read_ready(&mut Context::from_waker(&waker))
+    }
+
+    pub(crate) fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let waker = self.write_wakers.register(cx.waker());
+        self.stream
+            .poll_write_ready(&mut Context::from_waker(&waker))
+    }
+
+    pub(crate) fn op_timeout(&self) -> Option<Duration> {
+        *self.op_timeout.lock().unwrap()
+    }
+
+    pub(crate) fn set_op_timeout(&self, timeout: Duration) {
+        *self.op_timeout.lock().unwrap() = Some(timeout).filter(|t| !t.is_zero());
+    }
+
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match &*self.lost.lock().unwrap() {
+            Some(lost) => Err(lost.to_io_error()),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn fail(&self, session: &Session, e: io::Error) -> io::Error {
+        if !is_fatal(session, &self.stream, &e) {
+            return e;
+        }
+
+        self.poison(e)
+    }
+
+    // Records `e` as the reason the session is unusable, whatever it is, and
+    // wakes every task waiting on the socket so they fail with it too.
+    pub(crate) fn poison(&self, e: io::Error) -> io::Error {
+        let e = self
+            .lost
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| ConnectionLost {
+                source: Arc::new(e),
+            })
+            .to_io_error();
+
+        self.read_wakers.wake_by_ref();
+        self.write_wakers.wake_by_ref();
+        e
+    }
+}
+
+fn is_fatal(session: &Session, stream: &TcpStream, e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::NotConnected => return true,
+        _ => {}
+    }
+
+    // libssh2 swallows errno, socket failures only show up in the error code
+    if matches!(
+        ssh2::Error::last_session_error(session).map(|e| e.code()),
+        Some(ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_SEND))
+            | Some(ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_RECV))
+            | Some(ErrorCode::Session(raw::LIBSSH2_ERROR_SOCKET_DISCONNECT))
+    ) {
+        return true;
+    }
+
+    // and not always there either, a disconnect in the middle of the key
+    // exchange is reported as a kex failure, so ask the socket
+    match peek(stream, &mut [0]) {
+        Ok(n) => n == 0,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    }
+}
+
+// Peek without waiting, the stream is non-blocking.
+pub(crate) fn peek(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: initialized bytes are valid `MaybeUninit`s, and `peek` only writes to them
+    let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+    SockRef::from(stream).peek(buf)
+}
+
+#[derive(Default)]
+struct OpState {
+    held: bool,
+    prompting: bool,
+}
+
+pub(crate) struct OpGuard<'a> {
+    io: &'a Transport,
+}
+
+impl Drop for OpGuard<'_> {
+    fn drop(&mut self) {
+        self.io.op_state().held = false;
+        self.io.released.notify_one();
+    }
+}
+
+// The op lock is held by a keyboard-interactive prompt waiting for responses.
+#[derive(Debug)]
+pub(crate) struct Prompting;
+
+impl Prompting {
+    pub(crate) fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<Prompting>())
+    }
+}
+
+impl fmt::Display for Prompting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("session is waiting for keyboard-interactive responses")
+    }
+}
+
+impl Error for Prompting {}
+
+impl From<Prompting> for io::Error {
+    fn from(prompting: Prompting) -> Self {
+        io::Error::new(io::ErrorKind::ResourceBusy, prompting)
+    }
+}
+
+#[derive(Default)]
+struct WakerSet {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl WakerSet {
+    // Registered before polling, so a wakeup can't slip in between.
+    fn register(self: &Arc<Self>, waker: &Waker) -> Waker {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+
+        Waker::from(self.clone())
+    }
+}
+
+impl Wake for WakerSet {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let wakers = std::mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+// Handed to libssh2 in place of the socket. The fd stays owned by the tokio
+// stream in `Transport`, and the libssh2 session keeps the transport alive
+// until it's done with it, so the fd is closed exactly once.
+pub(crate) struct SessionStream(pub(crate) Arc<Transport>);
+
+#[cfg(unix)]
+impl AsRawFd for SessionStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.stream.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for SessionStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.0.stream.as_raw_socket()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Count {
+        fn get(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn waker_set_wakes_every_registered_task_once() {
+        let set = Arc::new(WakerSet::default());
+        let a = Arc::new(Count::default());
+        let b = Arc::new(Count::default());
+        let waker_a = Waker::from(a.clone());
+
+        set.register(&waker_a);
+        // the same task polling again must not be woken twice
+        set.register(&waker_a);
+        let waker = set.register(&Waker::from(b.clone()));
+
+        waker.wake_by_ref();
+        assert_eq!((a.get(), b.get()), (1, 1));
+
+        // woken tasks have to register again
+        waker.wake();
+        assert_eq!((a.get(), b.get()), (1, 1));
+    }
+
+    async fn transport() -> (Arc<Transport>, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (Arc::new(Transport::new(stream)), server)
+    }
+
+    #[tokio::test]
+    async fn lock_fails_fast_while_a_prompt_holds_it() {
+        let (io, _server) = transport().await;
+        let count = Arc::new(Count::default());
+        let waker = Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let _guard = io.lock().unwrap();
+        io.set_prompting(true);
+        let e = io::Error::from(io.lock().err().unwrap());
+        assert_eq!(e.kind(), io::ErrorKind::ResourceBusy);
+        assert!(Prompting::is(&e));
+        // not a broken connection
+        io.check().unwrap();
+
+        assert!(io.poll_prompt_done(&mut cx).is_pending());
+        io.set_prompting(false);
+        assert_eq!(count.get(), 1);
+        assert!(io.poll_prompt_done(&mut cx).is_ready());
+    }
+
+    #[tokio::test]
+    async fn lock_waits_for_other_holders_until_they_prompt() {
+        let (io, _server) = transport().await;
+
+        let guard = io.lock().unwrap();
+        let waiter = io.clone();
+        let blocked = std::thread::spawn(move || waiter.lock().is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+        drop(guard);
+        assert!(blocked.join().unwrap());
+
+        let _guard = io.lock().unwrap();
+        let waiter = io.clone();
+        let blocked = std::thread::spawn(move || waiter.lock().is_ok());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+        // a prompt starting sends it off to park instead
+        io.set_prompting(true);
+        assert!(!blocked.join().unwrap());
+    }
+}
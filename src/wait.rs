@@ -0,0 +1,226 @@
+use std::future;
+use std::io;
+use std::task::{ready, Context, Poll};
+
+use ssh2::{BlockDirections, Session};
+use tokio::io::{Interest, Ready};
+use tokio::time::{self, Instant};
+
+use crate::transport::{Prompting, Transport};
+
+// `None` if libssh2 isn't waiting on the socket. That happens when the
+// connection broke while the operation was in flight.
+pub(crate) fn interest(session: &Session) -> Option<Interest> {
+    match session.block_directions() {
+        BlockDirections::None => None,
+        BlockDirections::Inbound => Some(Interest::READABLE),
+        BlockDirections::Outbound => Some(Interest::WRITABLE),
+        BlockDirections::Both => Some(Interest::READABLE.add(Interest::WRITABLE)),
+    }
+}
+
+pub(crate) fn poll_ready(
+    session: &Session,
+    io: &Transport,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<Ready>> {
+    io.check()?;
+    let interest = {
+        let _guard = match io.lock() {
+            Ok(guard) => guard,
+            // a prompt holds the session, once it's answered the caller retries
+            Err(Prompting) => {
+                ready!(io.poll_prompt_done(cx));
+                return Poll::Ready(Ok(Ready::EMPTY));
+            }
+        };
+        match interest(session) {
+            Some(interest) => interest,
+            // nothing to wait for, let the caller retry the operation
+            None => return Poll::Ready(Ok(Ready::EMPTY)),
+        }
+    };
+
+    poll_interest(session, io, interest, cx).map_ok(|ready| {
+        if ready.is_readable() {
+            Ready::READABLE
+        } else {
+            Ready::WRITABLE
+        }
+    })
+}
+
+// Returns the direction out of `interest` that is ready.
+fn poll_interest(
+    session: &Session,
+    io: &Transport,
+    interest: Interest,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<Interest>> {
+    if interest.is_readable() {
+        if let Poll::Ready(r) = io.poll_read_ready(cx) {
+            return Poll::Ready(
+                r.map(|_| Interest::READABLE)
+                    .map_err(|e| io.fail(session, e)),
+            );
+        }
+    }
+    if interest.is_writable() {
+        if let Poll::Ready(r) = io.poll_write_ready(cx) {
+            return Poll::Ready(
+                r.map(|_| Interest::WRITABLE)
+                    .map_err(|e| io.fail(session, e)),
+            );
+        }
+    }
+
+    Poll::Pending
+}
+
+// Runs `op`, and if it would block samples the direction libssh2 blocked on
+// before another task gets the chance to call into the session. If libssh2
+// would block without waiting on the socket, `op` is retried once before
+// giving up, since there is nothing to wake us.
+pub(crate) fn attempt<R>(
+    session: &Session,
+    io: &Transport,
+    mut op: impl FnMut() -> io::Result<R>,
+) -> io::Result<Result<R, Interest>> {
+    io.check()?;
+
+    let _guard = io.lock()?;
+    for _ in 0..2 {
+        match run(session, io, &mut op) {
+            Ok(r) => return Ok(Ok(r)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(interest) = interest(session) {
+                    return Ok(Err(interest));
+                }
+            }
+            Err(e) => return Err(io.fail(session, e)),
+        }
+    }
+
+    let e = io::Error::other("libssh2 would block but is not waiting on the socket");
+    Err(io.fail(session, e))
+}
+
+// libssh2 does its own syscalls, so tokio never sees its EAGAIN and would keep
+// reporting the socket as ready, turning every wait in `poll_ready` into a
+// spin. If the socket is ready in the direction libssh2 was blocked on, `op`
+// runs inside `try_io`, which clears that readiness when `op` blocks on the
+// same direction again. The readiness is sampled before `op` runs, so an edge
+// arriving in between survives and no wakeup is lost.
+fn run<R>(
+    session: &Session,
+    io: &Transport,
+    op: &mut impl FnMut() -> io::Result<R>,
+) -> io::Result<R> {
+    let blocked = match interest(session) {
+        Some(blocked) => blocked,
+        None => return op(),
+    };
+
+    let mut res = None;
+    let _ = io.stream().try_io(blocked, || {
+        let r = op();
+        let stale = matches!(&r, Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+            && interest(session) == Some(blocked);
+        res = Some(r);
+        if stale {
+            Err(io::ErrorKind::WouldBlock.into())
+        } else {
+            Ok(())
+        }
+    });
+
+    // `try_io` skips `op` if the socket isn't ready yet
+    res.unwrap_or_else(op)
+}
+
+// The op timeout bounds the whole call. A call cut off by it is in the same
+// spot as one that would block, libssh2 resumes it when it is called again,
+// so the session stays usable. `AsyncSession::handshake` is the exception.
+pub(crate) async fn wait_io<R>(
+    session: &Session,
+    io: &Transport,
+    mut op: impl FnMut() -> io::Result<R>,
+) -> io::Result<R> {
+    let deadline = io.op_timeout().map(|timeout| Instant::now() + timeout);
+
+    loop {
+        match attempt(session, io, &mut op) {
+            Ok(Ok(r)) => return Ok(r),
+            // `poll_ready` parks until the prompt is answered
+            Err(e) if !Prompting::is(&e) => return Err(e),
+            _ => {}
+        }
+
+        let ready = future::poll_fn(|cx| poll_ready(session, io, cx));
+        match deadline {
+            Some(deadline) => match time::timeout_at(deadline, ready).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "ssh operation timed out",
+                    ))
+                }
+            },
+            None => ready.await?,
+        };
+    }
+}
+
+// The `poll_*` counterpart of `wait_io`.
+pub(crate) fn poll_io<R>(
+    session: &Session,
+    io: &Transport,
+    cx: &mut Context<'_>,
+    mut op: impl FnMut() -> io::Result<R>,
+) -> Poll<io::Result<R>> {
+    loop {
+        match attempt(session, io, &mut op) {
+            Ok(Ok(r)) => return Poll::Ready(Ok(r)),
+            Err(e) if !Prompting::is(&e) => return Poll::Ready(Err(e)),
+            _ => {}
+        }
+
+        ready!(poll_ready(session, io, cx))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn transport() -> (Transport, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (Transport::new(client), server)
+    }
+
+    #[tokio::test]
+    async fn blocking_on_nothing_is_an_error_not_a_panic() {
+        let (io, _server) = transport().await;
+        let session = Session::new().unwrap();
+        session.set_blocking(false);
+
+        let mut calls = 0;
+        let res = attempt(&session, &io, || -> io::Result<()> {
+            calls += 1;
+            Err(io::ErrorKind::WouldBlock.into())
+        });
+
+        assert_eq!(calls, 2, "retried once");
+        let e = res.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        // the connection itself is fine, so it isn't recorded as lost
+        io.check().unwrap();
+    }
+}
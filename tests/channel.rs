@@ -0,0 +1,73 @@
+mod common;
+
+#[tokio::test]
+async fn output_collects_stdout_stderr_and_exit_status() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel
+        .output("echo out; echo err >&2; exit 3")
+        .await
+        .unwrap();
+
+    assert_eq!(out.stdout, b"out\n");
+    assert_eq!(out.stderr, b"err\n");
+    assert_eq!(out.exit_status, 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn channels_of_one_session_work_from_separate_tasks() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = std::sync::Arc::new(server.session().await);
+
+    let tasks: Vec<_> = (0..2)
+        .map(|task| {
+            let session = session.clone();
+            tokio::spawn(async move {
+                for i in 0..20 {
+                    let mut channel = session.channel_session().await.unwrap();
+                    let command = format!("head -c 100000 /dev/zero; echo {} {}", task, i);
+                    let out = channel.output(&command).await.unwrap();
+                    assert_eq!(
+                        out.stdout.len(),
+                        100_000 + format!("{} {}\n", task, i).len()
+                    );
+                }
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn op_timeout_on_an_idle_wait_leaves_the_session_usable() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    session.set_op_timeout(std::time::Duration::from_millis(300));
+
+    let mut idle = session.channel_session().await.unwrap();
+    idle.exec("sleep 1").await.unwrap();
+    let e = idle.wait_eof().await.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    assert!(!e.get_ref().unwrap().is::<tokio_ssh2::ConnectionLost>());
+
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel.output("echo ok").await.unwrap();
+    assert_eq!(out.stdout, b"ok\n");
+
+    // the timed out wait can be picked up again
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    idle.wait_eof().await.unwrap();
+    idle.wait_close().await.unwrap();
+    assert_eq!(idle.exit_status().await.unwrap(), 0);
+}
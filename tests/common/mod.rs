@@ -0,0 +1,127 @@
+// Harness for the integration tests that need a real sshd: an OpenSSH
+// container built from `tests/docker`, one per test, torn down on drop.
+//
+// Only used when `TOKIO_SSH2_TEST_DOCKER=1`, `server` returns `None` otherwise
+// and the test skips itself.
+#![allow(dead_code)]
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio_ssh2::AsyncSession;
+
+pub const USER: &str = "tester";
+pub const PASSWORD: &str = "tester";
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct TestServer {
+    project: String,
+    pub addr: SocketAddr,
+}
+
+impl TestServer {
+    /// Private half of the key in the container's `authorized_keys`.
+    pub fn private_key(&self) -> PathBuf {
+        fixture().join("id_ed25519")
+    }
+
+    /// A session that completed the handshake and authenticated as `USER`.
+    pub async fn session(&self) -> AsyncSession {
+        let session = AsyncSession::connect(self.addr).await.unwrap();
+        session.userauth_password(USER, PASSWORD).await.unwrap();
+        session
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = compose(&self.project, &["down", "--volumes", "--timeout", "1"]);
+    }
+}
+
+/// Start a fresh sshd, or `None` with a note on stderr if docker tests are off.
+pub fn server() -> Option<TestServer> {
+    if std::env::var("TOKIO_SSH2_TEST_DOCKER").as_deref() != Ok("1") {
+        eprintln!("skipped: set TOKIO_SSH2_TEST_DOCKER=1 to run against an sshd container");
+        return None;
+    }
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let project = format!(
+        "tokio-ssh2-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let up = compose(&project, &["up", "--detach", "--build"]);
+    // torn down by drop even if startup fails from here on
+    let mut server = TestServer {
+        project,
+        addr: ([127, 0, 0, 1], 0).into(),
+    };
+    check(&up);
+
+    let port = compose(&server.project, &["port", "sshd", "22"]);
+    check(&port);
+    server.addr = String::from_utf8_lossy(&port.stdout)
+        .trim()
+        .parse()
+        .expect("docker compose port printed an address");
+
+    wait_for_banner(server.addr);
+    Some(server)
+}
+
+fn fixture() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("docker")
+}
+
+fn compose(project: &str, args: &[&str]) -> Output {
+    Command::new("docker")
+        .arg("compose")
+        .arg("--project-name")
+        .arg(project)
+        .arg("--file")
+        .arg(fixture().join("docker-compose.yml"))
+        .args(args)
+        .output()
+        .expect("failed to run docker compose")
+}
+
+fn check(output: &Output) {
+    assert!(
+        output.status.success(),
+        "docker compose failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// The port is published before sshd listens on it.
+fn wait_for_banner(addr: SocketAddr) {
+    let start = Instant::now();
+    loop {
+        let mut banner = [0u8; 4];
+        let res = TcpStream::connect(addr).and_then(|mut stream| {
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            stream.read_exact(&mut banner)
+        });
+        if res.is_ok() && &banner == b"SSH-" {
+            return;
+        }
+
+        assert!(
+            start.elapsed() < STARTUP_TIMEOUT,
+            "sshd in the test container didn't come up: {:?}",
+            res
+        );
+        thread::sleep(Duration::from_millis(200));
+    }
+}
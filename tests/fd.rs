@@ -0,0 +1,31 @@
+// In a binary of its own so no other test can take the fd number in between.
+#![cfg(unix)]
+
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use tokio_ssh2::AsyncSession;
+
+fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+#[tokio::test]
+async fn dropping_the_session_closes_its_socket_exactly_once() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let _server = listener.accept().unwrap();
+
+    let fd = client.as_raw_fd();
+    let session = AsyncSession::new(client).unwrap();
+    assert!(is_open(fd));
+
+    drop(session);
+    assert!(!is_open(fd), "fd {} leaked", fd);
+
+    // the lowest free number is handed out next, a second close would hit it
+    let reused = TcpListener::bind("127.0.0.1:0").unwrap();
+    assert_eq!(reused.as_raw_fd(), fd);
+    tokio::task::yield_now().await;
+    assert!(is_open(fd), "fd {} closed twice", fd);
+}
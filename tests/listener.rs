@@ -0,0 +1,63 @@
+mod common;
+
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+
+use futures_core::Stream;
+use tokio::io::AsyncReadExt;
+use tokio_ssh2::AsyncListener;
+
+async fn next(listener: &mut AsyncListener) -> Option<io::Result<tokio_ssh2::AsyncChannel>> {
+    poll_fn(|cx| Pin::new(&mut *listener).poll_next(cx)).await
+}
+
+#[tokio::test]
+async fn forwarded_connections_come_through_the_stream_until_close() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let (mut listener, port) = session
+        .channel_forward_listen(0, Some("127.0.0.1"), None)
+        .await
+        .unwrap();
+    assert_ne!(port, 0);
+
+    // busybox nc in the container plays the client of the forwarded port
+    let mut client = session.channel_session().await.unwrap();
+    let command = format!("echo hello | nc -w 5 127.0.0.1 {}", port);
+    let serve = async {
+        let mut channel = next(&mut listener).await.unwrap().unwrap();
+        let mut buf = [0u8; 6];
+        channel
+            .stream(0)
+            .unwrap()
+            .read_exact(&mut buf)
+            .await
+            .unwrap();
+        channel.send_eof().await.unwrap();
+        channel.close().await.unwrap();
+        channel.wait_close().await.unwrap();
+        buf
+    };
+    let (out, received) = tokio::join!(client.output(&command), serve);
+    out.unwrap();
+    assert_eq!(&received, b"hello\n");
+
+    listener.close().await.unwrap();
+
+    // the forward is gone on the server, so nothing listens on the port
+    let mut client = session.channel_session().await.unwrap();
+    let command = format!("nc -w 1 127.0.0.1 {} </dev/null; echo $?", port);
+    let out = client.output(&command).await.unwrap();
+    assert_ne!(
+        out.stdout, b"0\n",
+        "port {} still accepts connections",
+        port
+    );
+
+    assert!(next(&mut listener).await.is_none());
+    let e = listener.accept().await.err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::NotConnected);
+}
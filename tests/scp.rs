@@ -0,0 +1,77 @@
+mod common;
+
+use std::fs::FileTimes;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[tokio::test]
+async fn send_and_recv_file_round_trip_contents_and_mode() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+
+    let dir = std::env::temp_dir().join(format!("tokio-ssh2-scp-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let local = dir.join("up");
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    std::fs::write(&local, &data).unwrap();
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&local)
+        .unwrap()
+        .set_times(FileTimes::new().set_modified(mtime))
+        .unwrap();
+
+    session
+        .scp_send_file(&local, Path::new("scp-up"), 0o640)
+        .await
+        .unwrap();
+
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel.output("stat -c '%s %a %Y' scp-up").await.unwrap();
+    assert_eq!(out.stdout, b"100000 640 1500000000\n");
+
+    let down = dir.join("down");
+    let stat = session
+        .scp_recv_file(Path::new("scp-up"), &down)
+        .await
+        .unwrap();
+    assert_eq!(stat.size(), 100_000);
+    assert_eq!(stat.mode() & 0o7777, 0o640);
+    assert_eq!(std::fs::read(&down).unwrap(), data);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(&down).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+    }
+
+    // the session is still usable after both transfers
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel.output("rm scp-up").await.unwrap();
+    assert_eq!(out.exit_status, 0);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn recv_of_missing_file_leaves_the_session_usable() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+
+    let down = std::env::temp_dir().join(format!("tokio-ssh2-scp-missing-{}", std::process::id()));
+    let res = session
+        .scp_recv_file(Path::new("/no/such/file"), &down)
+        .await;
+    assert!(res.is_err());
+    assert!(!down.exists());
+
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel.output("true").await.unwrap();
+    assert_eq!(out.exit_status, 0);
+}
@@ -0,0 +1,38 @@
+// Compile-time checks, these only have to build.
+
+use std::path::Path;
+
+use tokio_ssh2::{
+    AsyncChannel, AsyncFile, AsyncSession, AsyncSftp, AsyncStream, ChannelReadHalf,
+    ChannelStdoutReader, ChannelWriteHalf,
+};
+
+fn assert_send<T: Send>() {}
+
+fn assert_send_val<T: Send>(_: &T) {}
+
+#[test]
+fn session_types_are_send() {
+    assert_send::<AsyncSession>();
+    assert_send::<AsyncChannel>();
+    assert_send::<AsyncSftp>();
+    assert_send::<AsyncFile>();
+}
+
+// The halves are meant to be moved into separate tasks.
+#[test]
+fn stream_types_are_send() {
+    assert_send::<AsyncStream>();
+    assert_send::<ChannelReadHalf>();
+    assert_send::<ChannelWriteHalf>();
+    assert_send::<ChannelStdoutReader>();
+}
+
+// The futures have to be `Send` as well to be awaited inside `tokio::spawn`.
+#[allow(dead_code)]
+fn futures_are_send(session: &AsyncSession, sftp: &AsyncSftp, channel: &mut AsyncChannel) {
+    assert_send_val(&session.channel_session());
+    assert_send_val(&session.sftp());
+    assert_send_val(&sftp.open(Path::new("file")));
+    assert_send_val(&channel.exec("true"));
+}
@@ -0,0 +1,269 @@
+use std::future::poll_fn;
+use std::io;
+use std::sync::Arc;
+use std::task::{Context, Wake, Waker};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tokio_ssh2::{AsyncSession, ConnectionLost, NotAnSshServer};
+
+// A session connected to a local listener, with the server end handed to the
+// caller to play the peer.
+async fn connected() -> (AsyncSession, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+
+    let session = AsyncSession::new(client.into_std().unwrap()).unwrap();
+    (session, server)
+}
+
+// Swallow whatever the client sends, so its writes never block.
+fn drain(mut server: TcpStream) -> tokio::task::JoinHandle<TcpStream> {
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = server.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+        server
+    })
+}
+
+struct Noop;
+
+impl Wake for Noop {
+    fn wake(self: Arc<Self>) {}
+}
+
+#[tokio::test]
+async fn compat_presets_apply_before_handshake() {
+    let (session, _server) = connected().await;
+
+    session.compat_old_kex(true).await.unwrap();
+    session.compat_old_kex(false).await.unwrap();
+    session.compat_legacy_device().await.unwrap();
+    session.compat_disable_strict_kex(false).await.unwrap();
+
+    let e = session.compat_disable_strict_kex(true).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+}
+
+#[tokio::test]
+async fn http_server_is_not_an_ssh_server() {
+    let (mut session, mut server) = connected().await;
+    server
+        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    server.shutdown().await.unwrap();
+
+    let e = session.handshake().await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let e = e
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<NotAnSshServer>()
+        .unwrap();
+    assert!(e.first_bytes.starts_with(b"HTTP/1.1 400"));
+}
+
+#[tokio::test]
+async fn banner_check_skips_pre_version_lines_once() {
+    let (mut session, mut server) = connected().await;
+    session.set_banner_timeout(Duration::from_millis(200));
+
+    server.write_all(b"welcome\r\nSS").await.unwrap();
+    let server = tokio::spawn(async move {
+        time::sleep(Duration::from_millis(50)).await;
+        server.write_all(b"H-2.0-test\r\n").await.unwrap();
+        server
+    });
+
+    // libssh2 takes the version line and waits for the server's KEXINIT
+    let res = time::timeout(Duration::from_millis(500), session.handshake()).await;
+    assert!(res.is_err(), "handshake finished: {:?}", res);
+
+    // the banner is gone from the socket now, a retry must not look for it
+    let res = time::timeout(Duration::from_millis(500), session.handshake()).await;
+    assert!(res.is_err(), "handshake finished: {:?}", res);
+
+    let mut server = server.await.unwrap();
+    // half a packet length, libssh2 reads it and blocks again
+    server.write_all(&[0, 0]).await.unwrap();
+    let _server = drain(server);
+
+    let ready = poll_fn(|cx| session.poll_io_ready(cx)).await.unwrap();
+    assert!(ready.is_readable());
+    let res = time::timeout(Duration::from_millis(100), session.handshake()).await;
+    assert!(res.is_err(), "handshake finished: {:?}", res);
+
+    // the readiness libssh2 used up must not be reported again
+    let waker = Waker::from(Arc::new(Noop));
+    let mut cx = Context::from_waker(&waker);
+    assert!(session.poll_io_ready(&mut cx).is_pending());
+}
+
+#[tokio::test]
+async fn closed_connection_fails_fast_with_connection_lost() {
+    let (mut session, mut server) = connected().await;
+    server.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+    drop(server);
+
+    let e = session.handshake().await.unwrap_err();
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+
+    // no more round trips, every later call reports the same loss
+    let e = time::timeout(Duration::from_secs(1), session.auth_methods("user"))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+    let e = time::timeout(Duration::from_secs(1), session.channel_session())
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+}
+
+async fn open_err(session: &AsyncSession, unchecked: bool, window: u32, packet: u32) -> io::Error {
+    let res = if unchecked {
+        session
+            .channel_open_unchecked("session", window, packet, None)
+            .await
+    } else {
+        session.channel_open("session", window, packet, None).await
+    };
+    res.err().unwrap()
+}
+
+#[tokio::test]
+async fn channel_open_validates_before_any_io() {
+    let (session, server) = connected().await;
+
+    for unchecked in [false, true] {
+        let e = open_err(&session, unchecked, 1 << 20, 0).await;
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("packet_size must not be 0"), "{}", e);
+
+        // the value as passed is checked, not the clamped one
+        let e = open_err(&session, unchecked, 2000, 100_000).await;
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("2000"), "{}", e);
+        assert!(e.to_string().contains("100000"), "{}", e);
+    }
+
+    let mut buf = [0u8; 1];
+    let e = server.try_read(&mut buf).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock, "client sent data");
+}
+
+#[tokio::test]
+async fn op_timeout_in_the_handshake_poisons_the_session() {
+    let (mut session, mut server) = connected().await;
+    session.set_op_timeout(Duration::from_millis(300));
+    // a banner and then nothing, libssh2 waits for the KEXINIT forever
+    server.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+    let _server = drain(server);
+
+    let e = time::timeout(Duration::from_secs(2), session.handshake())
+        .await
+        .expect("op timeout didn't fire")
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+
+    // a retry would resume a key exchange that is half over
+    let e = time::timeout(Duration::from_millis(100), session.handshake())
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+}
+
+#[tokio::test]
+async fn new_over_reports_a_peer_that_isnt_ssh() {
+    let (client, mut server) = tokio::io::duplex(1024);
+    let mut session = AsyncSession::new_over(client).await.unwrap();
+
+    server
+        .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+        .await
+        .unwrap();
+    drop(server);
+
+    let e = time::timeout(Duration::from_secs(2), session.handshake())
+        .await
+        .unwrap()
+        .unwrap_err();
+    let e = e
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<NotAnSshServer>()
+        .unwrap();
+    assert!(e.first_bytes.starts_with(b"HTTP/1.1 400"));
+}
+
+#[tokio::test]
+async fn new_over_closed_stream_is_a_lost_connection() {
+    let (client, mut server) = tokio::io::duplex(1024);
+    let mut session = AsyncSession::new_over(client).await.unwrap();
+
+    server.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+    drop(server);
+
+    let e = time::timeout(Duration::from_secs(2), session.handshake())
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+}
+
+#[tokio::test]
+async fn unconfigured_keepalive_keeps_polling_until_the_connection_is_lost() {
+    let (mut session, mut server) = connected().await;
+    let mut keepalive = session.spawn_keepalive();
+
+    // with no interval set libssh2 sends nothing and the task sleeps 1s
+    time::sleep(Duration::from_millis(1500)).await;
+    assert!(keepalive.error().is_none());
+
+    server.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+    drop(server);
+    let e = session.handshake().await.unwrap_err();
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+
+    // the next poll sees the loss and the task stops with it
+    let e = time::timeout(Duration::from_millis(1500), keepalive.failed())
+        .await
+        .expect("keepalive didn't poll again");
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+    let e = keepalive.error().unwrap();
+    assert!(e.get_ref().unwrap().is::<ConnectionLost>(), "{:?}", e);
+}
+
+#[tokio::test]
+async fn dropping_the_keepalive_handle_stops_its_task() {
+    let (session, mut server) = connected().await;
+    let keepalive = session.spawn_keepalive();
+    drop(session);
+
+    // the task still holds the connection open
+    let mut buf = [0u8; 1];
+    let res = time::timeout(Duration::from_millis(200), server.read(&mut buf)).await;
+    assert!(res.is_err(), "connection closed: {:?}", res);
+
+    drop(keepalive);
+    let n = time::timeout(Duration::from_secs(1), server.read(&mut buf))
+        .await
+        .expect("keepalive task still running")
+        .unwrap();
+    assert_eq!(n, 0);
+}
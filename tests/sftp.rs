@@ -0,0 +1,343 @@
+mod common;
+
+use std::fs::FileTimes;
+use std::future::poll_fn;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures_core::Stream;
+use ssh2::FileStat;
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio_ssh2::{AsyncFile, TransferError, TransferOptions};
+
+#[tokio::test]
+async fn stat_of_missing_path_is_not_found() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let sftp = session.sftp().await.unwrap();
+
+    let e = sftp.stat("/no/such/file").await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    let e = sftp.lstat("/no/such/file").await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn stat_realpath_and_readlink_return_their_results() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    // OpenSSH swaps the arguments of SSH_FXP_SYMLINK, let ln sort it out
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel
+        .output("mkdir -p dir && printf 12345 > dir/file && ln -s dir/file link")
+        .await
+        .unwrap();
+    assert_eq!(out.exit_status, 0);
+
+    let sftp = session.sftp().await.unwrap();
+    let home = sftp.realpath(".").await.unwrap();
+    assert_eq!(home, Path::new("/home/tester"));
+
+    let stat = sftp.stat("link").await.unwrap();
+    assert!(stat.is_file());
+    assert_eq!(stat.size, Some(5));
+    let lstat = sftp.lstat("link").await.unwrap();
+    assert!(lstat.file_type().is_symlink());
+
+    assert_eq!(sftp.readlink("link").await.unwrap(), Path::new("dir/file"));
+    assert_eq!(
+        sftp.realpath("link").await.unwrap(),
+        Path::new("/home/tester/dir/file")
+    );
+}
+
+#[tokio::test]
+async fn read_with_limit_rejects_files_over_the_limit() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let mut channel = session.channel_session().await.unwrap();
+    channel.output("printf 12345 > five").await.unwrap();
+
+    let sftp = session.sftp().await.unwrap();
+    assert_eq!(sftp.read_with_limit("five", 5).await.unwrap(), b"12345");
+    let e = sftp.read_with_limit("five", 4).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::FileTooLarge);
+}
+
+#[tokio::test]
+async fn read_dir_streams_entries_and_closes() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let mut channel = session.channel_session().await.unwrap();
+    channel
+        .output("mkdir -p list && touch list/a list/b list/c")
+        .await
+        .unwrap();
+
+    let sftp = session.sftp().await.unwrap();
+    let mut entries = sftp.read_dir("list").await.unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = poll_fn(|cx| Pin::new(&mut entries).poll_next(cx)).await {
+        names.push(entry.unwrap().0);
+    }
+    entries.close().await.unwrap();
+
+    names.sort();
+    assert_eq!(names, ["list/a", "list/b", "list/c"].map(PathBuf::from));
+
+    // stopping early
+    let mut entries = sftp.read_dir("list").await.unwrap();
+    poll_fn(|cx| Pin::new(&mut entries).poll_next(cx))
+        .await
+        .unwrap()
+        .unwrap();
+    entries.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn create_and_remove_dir_all() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let sftp = session.sftp().await.unwrap();
+
+    sftp.create_dir_all("tree/a/b", None).await.unwrap();
+    sftp.create_dir_all("tree/c", Some(0o700)).await.unwrap();
+    sftp.create("tree/a/b/file".as_ref())
+        .await
+        .unwrap()
+        .close()
+        .await
+        .unwrap();
+    assert_eq!(
+        sftp.stat("tree/a").await.unwrap().perm.unwrap() & 0o777,
+        0o755
+    );
+    assert_eq!(
+        sftp.stat("tree/c").await.unwrap().perm.unwrap() & 0o777,
+        0o700
+    );
+
+    // a link out of the tree must be unlinked, not followed
+    let mut channel = session.channel_session().await.unwrap();
+    let out = channel
+        .output("mkdir outside && touch outside/keep && ln -s /home/tester/outside tree/a/link")
+        .await
+        .unwrap();
+    assert_eq!(out.exit_status, 0);
+
+    sftp.remove_dir_all("tree").await.unwrap();
+    let e = sftp.stat("tree").await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(sftp.stat("outside/keep").await.unwrap().is_file());
+
+    let e = sftp.remove_dir_all("tree").await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(e.to_string().starts_with("tree: "), "{}", e);
+}
+
+#[tokio::test]
+async fn transfers_preserve_both_times_and_report_progress() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let sftp = session.sftp().await.unwrap();
+
+    let dir = std::env::temp_dir().join(format!("tokio-ssh2-transfer-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let local = dir.join("up");
+    std::fs::write(&local, vec![7u8; 100_000]).unwrap();
+    let atime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&local)
+        .unwrap()
+        .set_times(FileTimes::new().set_accessed(atime).set_modified(mtime))
+        .unwrap();
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let seen = calls.clone();
+    let opts = TransferOptions {
+        preserve_mtime: true,
+        progress: Some(Arc::new(move |done, total| {
+            seen.lock().unwrap().push((done, total))
+        })),
+        ..Default::default()
+    };
+
+    let n = sftp.upload(&local, "up", opts.clone()).await.unwrap();
+    assert_eq!(n, 100_000);
+    assert_eq!(
+        calls.lock().unwrap().last(),
+        Some(&(100_000, Some(100_000)))
+    );
+    let stat = sftp.stat("up").await.unwrap();
+    assert_eq!(stat.atime, Some(1_000_000_000));
+    assert_eq!(stat.mtime, Some(1_500_000_000));
+
+    let down = dir.join("down");
+    sftp.download("up", &down, opts).await.unwrap();
+    let meta = std::fs::metadata(&down).unwrap();
+    assert_eq!(meta.len(), 100_000);
+    assert_eq!(meta.modified().unwrap(), mtime);
+    assert_eq!(meta.accessed().unwrap(), atime);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Reads `file` to the end in 8 KiB pieces and counts how often a read had to
+// wait for the server, roughly one round trip each.
+async fn count_waits(file: &mut AsyncFile) -> (usize, usize) {
+    let mut buf = [0u8; 8192];
+    let (mut total, mut waits) = (0, 0);
+    loop {
+        let n = poll_fn(|cx| {
+            let mut buf = ReadBuf::new(&mut buf);
+            match Pin::new(&mut *file).poll_read(cx, &mut buf) {
+                Poll::Pending => {
+                    waits += 1;
+                    Poll::Pending
+                }
+                Poll::Ready(res) => Poll::Ready(res.map(|_| buf.filled().len())),
+            }
+        })
+        .await
+        .unwrap();
+        if n == 0 {
+            return (total, waits);
+        }
+        total += n;
+    }
+}
+
+#[tokio::test]
+async fn read_ahead_cuts_the_round_trips_of_small_reads() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let mut channel = session.channel_session().await.unwrap();
+    channel
+        .output("head -c 4194304 /dev/zero > big")
+        .await
+        .unwrap();
+    let sftp = session.sftp().await.unwrap();
+
+    let mut file = sftp.open(Path::new("big")).await.unwrap();
+    let (total, plain) = count_waits(&mut file).await;
+    assert_eq!(total, 4 << 20);
+
+    let mut file = sftp.open(Path::new("big")).await.unwrap();
+    file.set_read_ahead(1 << 20);
+    let (total, ahead) = count_waits(&mut file).await;
+    assert_eq!(total, 4 << 20);
+
+    assert!(
+        ahead * 4 < plain,
+        "{} waits with read-ahead, {} without",
+        ahead,
+        plain
+    );
+}
+
+#[tokio::test]
+async fn stat_and_setstat_see_buffered_writes() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let sftp = session.sftp().await.unwrap();
+
+    let mut file = sftp.create(Path::new("buffered")).await.unwrap();
+    file.set_write_buffer(1 << 16);
+    file.write_all(&[1; 100]).await.unwrap();
+    assert_eq!(file.stat().await.unwrap().size, Some(100));
+
+    file.write_all(&[2; 100]).await.unwrap();
+    // truncating after the buffered writes, not before them
+    let stat = FileStat {
+        size: Some(50),
+        uid: None,
+        gid: None,
+        perm: None,
+        atime: None,
+        mtime: None,
+    };
+    file.setstat(stat).await.unwrap();
+    file.close().await.unwrap();
+    assert_eq!(sftp.stat("buffered").await.unwrap().size, Some(50));
+}
+
+#[tokio::test]
+async fn upload_to_a_full_disk_reports_how_much_was_written() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let sftp = session.sftp().await.unwrap();
+
+    let dir = std::env::temp_dir().join(format!("tokio-ssh2-full-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let local = dir.join("big");
+    std::fs::write(&local, vec![7u8; 3 << 20]).unwrap();
+
+    let e = sftp
+        .upload(&local, "/full/big", TransferOptions::default())
+        .await
+        .unwrap_err();
+    // OpenSSH has no SFTP status for a full disk, other servers report it
+    assert!(
+        matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::Other),
+        "{:?}",
+        e
+    );
+    let partial = e
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<TransferError>()
+        .unwrap()
+        .bytes_written_before_error;
+    assert!(partial > 0 && partial <= 1 << 20, "{}", partial);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_download_leaves_a_local_file_it_never_opened() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = server.session().await;
+    let sftp = session.sftp().await.unwrap();
+
+    let local = std::env::temp_dir().join(format!("tokio-ssh2-keep-{}", std::process::id()));
+    std::fs::write(&local, b"precious").unwrap();
+
+    let opts = TransferOptions {
+        delete_on_error: true,
+        ..Default::default()
+    };
+    let e = sftp
+        .download("/no/such/file", &local, opts)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert_eq!(std::fs::read(&local).unwrap(), b"precious");
+
+    std::fs::remove_file(&local).unwrap();
+}
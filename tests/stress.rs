@@ -0,0 +1,63 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time;
+
+const TASKS: u64 = 8;
+const ITERATIONS: u64 = 500;
+
+// xorshift, good enough to shuffle the order of operations per task
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+// Many tasks hammering one session with a random mix of operations. Any of
+// them sampling the wrong block direction shows up as a panic, an error, or a
+// task stalled on readiness that never comes.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_random_ops_neither_panic_nor_stall() {
+    let Some(server) = common::server() else {
+        return;
+    };
+    let session = Arc::new(server.session().await);
+    let sftp = Arc::new(session.sftp().await.unwrap());
+
+    let tasks = (0..TASKS).map(|task| {
+        let session = session.clone();
+        let sftp = sftp.clone();
+        tokio::spawn(async move {
+            let mut state = 0x9e37_79b9_7f4a_7c15 ^ (task + 1);
+            for _ in 0..ITERATIONS {
+                match next(&mut state) % 4 {
+                    0 => {
+                        session.keepalive_send().await.unwrap();
+                    }
+                    1 => {
+                        sftp.stat("/etc/passwd").await.unwrap();
+                    }
+                    2 => {
+                        sftp.realpath(".").await.unwrap();
+                    }
+                    _ => {
+                        let mut channel = session.channel_session().await.unwrap();
+                        let out = channel.output("echo ok").await.unwrap();
+                        assert_eq!(out.stdout, b"ok\n");
+                    }
+                }
+            }
+        })
+    });
+    let tasks: Vec<_> = tasks.collect();
+
+    for task in tasks {
+        time::timeout(Duration::from_secs(300), task)
+            .await
+            .expect("a task stalled")
+            .expect("a task panicked");
+    }
+}